@@ -1,53 +1,460 @@
 import os
 import sys
 import json
+import argparse
+import contextlib
+import difflib
+import hashlib
+import math
+import random
+import shutil
+import subprocess
+import threading
+import time
 import requests
+from collections import deque
+from concurrent.futures import ThreadPoolExecutor
+from email.utils import parsedate_to_datetime
 from github import Github
-from git import Git, Repo
+from git import Git, GitCommandError, Repo
+from urllib3.util.retry import Retry
 import re
 
+try:
+    import tomllib
+except ImportError:  # Python < 3.11
+    import tomli as tomllib
 
-def get_contextual_files(repo_path):
+
+MANUAL_PATH = "developer_manual.md"
+EXAMPLES_PATH = "examples"
+
+
+def get_contextual_files(
+    repo_path, manual_path=MANUAL_PATH, examples_path=EXAMPLES_PATH
+):
     manual_content = ""
-    example_contents = ""
+    examples = []
 
-    repo_path = os.environ.get("GITHUB_WORKSPACE", "/github/workspace")
+    if not repo_path:
+        repo_path = os.environ.get("GITHUB_WORKSPACE", "/github/workspace")
 
     # Read developer manual
-    manual_path = os.path.join(repo_path, "developer_manual.md")
-    if os.path.exists(manual_path):
-        with open(manual_path, "r") as f:
+    manual_file = os.path.join(repo_path, manual_path)
+    if os.path.exists(manual_file):
+        with open(manual_file, "r") as f:
             manual_content = f.read()
     else:
-        print("[DEBUG] No developer_manual.md found.")
+        print(f"[DEBUG] No {manual_path} found.")
 
     # Read example files
-    examples_path = os.path.join(repo_path, "examples")
-    if os.path.exists(examples_path):
-        for root, dirs, files in os.walk(examples_path):
-            for file in files:
+    examples_dir = os.path.join(repo_path, examples_path)
+    if os.path.exists(examples_dir):
+        for root, dirs, files in os.walk(examples_dir):
+            dirs.sort()
+            for file in sorted(files):
                 with open(os.path.join(root, file), "r") as f:
                     content = f.read()
-                    example_contents += f"\n### Example File: {file}\n{content}"
+                    examples.append({"name": file, "content": content})
     else:
-        print("[DEBUG] No examples directory found.")
+        print(f"[DEBUG] No {examples_path} directory found.")
 
-    return manual_content, example_contents
+    return manual_content, examples
 
 
-def get_changed_files(repo, base_branch, head_branch, file_extensions):
-    origin = repo.remotes.origin
-    print("[DEBUG] Fetching all branches...")
-    origin.fetch()
+def find_nearest_path(repo_path, file_path, relative_path):
+    # Looks for relative_path below the file's directory and each of its
+    # ancestors, ending at the repository root
+    directory = os.path.dirname(file_path)
+    while True:
+        candidate = os.path.normpath(os.path.join(directory, relative_path))
+        if os.path.exists(os.path.join(repo_path, candidate)) or not directory:
+            return candidate
+        directory = os.path.dirname(directory)
 
-    if base_branch not in repo.heads:
-        print(f"[DEBUG] Base branch {base_branch} not found locally. Creating it.")
-        repo.create_head(base_branch, origin.refs[base_branch])
-    else:
-        repo.heads[base_branch].set_tracking_branch(origin.refs[base_branch])
 
-    print(f"[DEBUG] Checking out head branch: {head_branch}")
-    repo.git.checkout(head_branch)
+def get_file_contexts(
+    repo_path, filenames, manual_path=MANUAL_PATH, examples_path=EXAMPLES_PATH
+):
+    # Every file uses the manual and examples nearest to it, e.g. those of its
+    # crate in a monorepo, falling back to the ones at the repository root
+    loaded = {}
+    file_contexts = {}
+    for filename in filenames:
+        paths = (
+            find_nearest_path(repo_path, filename, manual_path),
+            find_nearest_path(repo_path, filename, examples_path),
+        )
+        if paths not in loaded:
+            manual_content, examples = get_contextual_files(repo_path, *paths)
+            loaded[paths] = {
+                "manual_path": paths[0],
+                "manual_content": manual_content,
+                "examples": examples,
+            }
+        if paths != (os.path.normpath(manual_path), os.path.normpath(examples_path)):
+            print(f"[DEBUG] {filename} uses {paths[0]} and {paths[1]}")
+        file_contexts[filename] = loaded[paths]
+    return file_contexts
+
+
+# Rule ID prefixes for the sections of developer_manual.md, other sections use
+# their first word
+SECTION_RULE_PREFIXES = {
+    "File Structure": "FILE",
+    "Naming Conventions": "NAMING",
+    "Documentation": "DOC",
+    "Error Handling": "ERR",
+    "Testing": "TEST",
+    "Dependencies": "DEP",
+    "Performance": "PERF",
+    "Safety": "SAFE",
+    "Tooling": "TOOL",
+    "Code Organization": "ORG",
+    "Review Process": "REVIEW",
+}
+# Pins a rule's ID so rewording the rule doesn't change it, e.g.
+# "- Constants: `SCREAMING_SNAKE_CASE` <!-- rule: NAMING-CONST -->"
+RULE_ID_COMMENT_RE = re.compile(r"\s*<!--\s*rule:\s*([A-Za-z0-9-]+)\s*-->")
+MANUAL_HEADING_RE = re.compile(r"^(#+)\s+(.*?)\s*#*\s*$")
+MANUAL_RULE_RE = re.compile(r"^\s*(?:[-*+]|\d+\.)\s+(.*\S)")
+RULE_ID_STOPWORDS = {
+    "a",
+    "an",
+    "the",
+    "of",
+    "for",
+    "in",
+    "to",
+    "and",
+    "or",
+    "on",
+    "by",
+    "via",
+    "with",
+    "when",
+    "if",
+    "over",
+    "be",
+    "is",
+    "are",
+    "must",
+    "should",
+    "every",
+    "all",
+    "any",
+    "use",
+    "run",
+    "maximum",
+    "minimum",
+    "max",
+    "min",
+}
+# Derived IDs read like hand-picked ones, e.g. "Maximum function length" is
+# ORG-FN-LEN rather than ORG-FUNCTION-LENGTH
+RULE_ID_ABBREVIATIONS = {
+    "ARGUMENT": "ARG",
+    "CONFIGURATION": "CONFIG",
+    "CONSTANT": "CONST",
+    "DEPENDENCY": "DEP",
+    "DOCUMENTATION": "DOC",
+    "ERROR": "ERR",
+    "FUNCTION": "FN",
+    "LENGTH": "LEN",
+    "MODULE": "MOD",
+    "PARAMETER": "PARAM",
+    "REFERENCE": "REF",
+    "VARIABLE": "VAR",
+}
+# Cited by findings that no rule of the manual covers
+GENERAL_RULE_ID = "GENERAL"
+
+
+def get_heading_anchor(heading):
+    # Same slugs GitHub generates for Markdown headings
+    anchor = re.sub(r"[^\w\- ]", "", heading.lower())
+    return anchor.replace(" ", "-")
+
+
+def get_rule_id_words(text):
+    # The label before a colon names the rule better than the full sentence
+    label = text.split(":", 1)[0] if ":" in text[:40] else text
+    words = []
+    for word in re.findall(r"[A-Za-z]+", label):
+        if word.lower() in RULE_ID_STOPWORDS:
+            continue
+        word = word.upper()
+        if word.endswith("IES"):
+            word = word[:-3] + "Y"
+        elif word.endswith(("CHES", "SHES", "SSES", "XES")):
+            word = word[:-2]
+        elif word.endswith("S") and not word.endswith(("SS", "US", "IS")):
+            word = word[:-1]
+        word = RULE_ID_ABBREVIATIONS.get(word, word)
+        if word not in words:
+            words.append(word)
+    return words
+
+
+def make_rule_id(prefix, text, word_count=2):
+    # "Use `///` for doc comments" in Documentation is DOC-COMMENT, not DOC-DOC-...
+    words = [word for word in get_rule_id_words(text) if word != prefix]
+    return "-".join([prefix] + words[:word_count])
+
+
+def assign_rule_ids(entries):
+    # Clashing derived IDs all take more words of their rule, so an ID doesn't
+    # depend on the order of the rules or on rules added after it
+    pinned_ids = {entry["id"] for entry in entries if entry["id"]}
+    derived = [entry for entry in entries if not entry["id"]]
+    word_counts = {id(entry): 2 for entry in derived}
+
+    def derive(entry, extra=0):
+        return make_rule_id(
+            entry["prefix"], entry["text"], word_counts[id(entry)] + extra
+        )
+
+    while True:
+        ids = [derive(entry) for entry in derived]
+        clashes = {i for i in ids if ids.count(i) > 1 or i in pinned_ids}
+        growing = [
+            entry
+            for entry, rule_id in zip(derived, ids)
+            if rule_id in clashes and derive(entry, 1) != rule_id
+        ]
+        if not growing:
+            break
+        for entry in growing:
+            word_counts[id(entry)] += 1
+    for entry, rule_id in zip(derived, ids):
+        if rule_id in clashes:
+            # Same words, told apart by the rule's wording
+            digest = hashlib.sha256(entry["text"].encode("utf-8")).hexdigest()
+            rule_id = f"{rule_id}-{digest[:4].upper()}"
+        entry["id"] = rule_id
+
+
+def parse_rule_catalogue(manual_content):
+    entries = []
+    section = None
+    in_code_block = False
+    for line in manual_content.splitlines():
+        if line.lstrip().startswith("```"):
+            in_code_block = not in_code_block
+            continue
+        if in_code_block:
+            continue
+        heading = MANUAL_HEADING_RE.match(line)
+        if heading:
+            section = heading.group(2)
+            continue
+        item = MANUAL_RULE_RE.match(line)
+        if not item or section is None:
+            continue
+        text = item.group(1)
+        pinned = RULE_ID_COMMENT_RE.search(text)
+        prefix = SECTION_RULE_PREFIXES.get(section)
+        if not prefix:
+            prefix = re.findall(r"[A-Za-z]+", section)[0].upper()
+        entries.append(
+            {
+                "id": pinned.group(1).upper() if pinned else None,
+                "prefix": prefix,
+                "section": section,
+                "text": RULE_ID_COMMENT_RE.sub("", text).strip(),
+            }
+        )
+    assign_rule_ids(entries)
+
+    rules = []
+    seen_ids = set()
+    for entry in entries:
+        # Repeated IDs (pinned twice, or identical rules) would make citations
+        # ambiguous, number them instead
+        unique_id = entry["id"]
+        suffix = 2
+        while unique_id in seen_ids:
+            unique_id = f"{entry['id']}-{suffix}"
+            suffix += 1
+        seen_ids.add(unique_id)
+        rules.append(
+            {
+                "id": unique_id,
+                "section": entry["section"],
+                "anchor": get_heading_anchor(entry["section"]),
+                "text": entry["text"],
+            }
+        )
+    return rules
+
+
+def format_rule_catalogue(rules):
+    return "\n".join(
+        f"- {rule['id']} ({rule['section']}): {rule['text']}" for rule in rules
+    )
+
+
+def get_manual_url(repo_full_name, commit_id, manual_path=MANUAL_PATH):
+    server_url = os.environ.get("GITHUB_SERVER_URL", "https://github.com")
+    return f"{server_url}/{repo_full_name}/blob/{commit_id}/{manual_path}"
+
+
+def get_rule_catalogues(file_contexts, repo_full_name="", commit_id=""):
+    # The rules and URL of every manual in use, by manual path, outermost first
+    catalogues = {}
+    contexts = sorted(
+        file_contexts.values(),
+        key=lambda context: context["manual_path"].count(os.sep),
+    )
+    for context in contexts:
+        manual_path = context["manual_path"]
+        if manual_path in catalogues:
+            continue
+        manual_url = ""
+        if repo_full_name:
+            manual_url = get_manual_url(repo_full_name, commit_id, manual_path)
+        catalogues[manual_path] = {
+            "rules": parse_rule_catalogue(context["manual_content"]),
+            "manual_url": manual_url,
+        }
+    return catalogues
+
+
+def link_findings_to_rules(comments, file_contexts, catalogues):
+    # Findings link to the section of the manual their file was reviewed with
+    for comment in comments:
+        context = file_contexts.get(comment["filename"])
+        if not context:
+            continue
+        catalogue = catalogues[context["manual_path"]]
+        sections = {rule["id"]: rule["anchor"] for rule in catalogue["rules"]}
+        anchor = sections.get(comment["rule_id"])
+        if anchor and catalogue["manual_url"]:
+            comment["rule_url"] = f"{catalogue['manual_url']}#{anchor}"
+
+
+def count_rule_violations(comments):
+    counts = {}
+    for comment in comments:
+        counts[comment["rule_id"]] = counts.get(comment["rule_id"], 0) + 1
+    # Most violated first, ties in ID order so the output is stable
+    return dict(sorted(counts.items(), key=lambda item: (-item[1], item[0])))
+
+
+IDENTIFIER_RE = re.compile(r"[A-Za-z_][A-Za-z0-9_]*")
+CAMEL_CASE_RE = re.compile(r"[A-Z]+(?=[A-Z][a-z])|[A-Z]?[a-z]+|[A-Z]+|[0-9]+")
+# Too common in Rust code to say anything about relevance
+KEYWORD_STOPLIST = {
+    "as",
+    "async",
+    "await",
+    "bad",
+    "const",
+    "crate",
+    "dyn",
+    "else",
+    "enum",
+    "example",
+    "false",
+    "file",
+    "for",
+    "good",
+    "impl",
+    "let",
+    "match",
+    "mod",
+    "mut",
+    "new",
+    "pub",
+    "ref",
+    "return",
+    "self",
+    "src",
+    "static",
+    "struct",
+    "super",
+    "the",
+    "this",
+    "trait",
+    "true",
+    "type",
+    "use",
+    "where",
+    "while",
+    "with",
+    "wrong",
+}
+
+
+def extract_keywords(text):
+    # Identifiers plus their snake_case and CamelCase parts, so that e.g.
+    # `ConfigError` also matches an example about `config`
+    keywords = set()
+    for identifier in IDENTIFIER_RE.findall(text):
+        keywords.add(identifier.lower())
+        for part in identifier.split("_"):
+            keywords.update(word.lower() for word in CAMEL_CASE_RE.findall(part))
+    return {k for k in keywords if len(k) >= 3 and k not in KEYWORD_STOPLIST}
+
+
+def select_examples(examples, filename, diff_content, max_examples, token_budget):
+    if not examples or max_examples <= 0:
+        return ""
+
+    query = extract_keywords(filename) | extract_keywords(diff_content)
+    example_keywords = [extract_keywords(e["content"]) for e in examples]
+    # Rarer shared keywords say more about relevance
+    doc_freq = {}
+    for keywords in example_keywords:
+        for keyword in keywords:
+            doc_freq[keyword] = doc_freq.get(keyword, 0) + 1
+
+    stem = os.path.splitext(os.path.basename(filename))[0].lower()
+    scored = []
+    for example, keywords in zip(examples, example_keywords):
+        score = sum(
+            math.log(1 + len(examples) / doc_freq[keyword])
+            for keyword in query & keywords
+        )
+        if os.path.splitext(example["name"])[0].lower() == stem:
+            score += 10
+        scored.append((score, example))
+    scored.sort(key=lambda item: item[0], reverse=True)
+
+    selected = []
+    remaining = token_budget
+    for score, example in scored:
+        if len(selected) >= max_examples or score <= 0:
+            break
+        text = f"\n### Example File: {example['name']}\n{example['content']}"
+        tokens = estimate_tokens(text)
+        if tokens > remaining:
+            continue
+        selected.append((score, example["name"], text))
+        remaining -= tokens
+
+    print(
+        f"[DEBUG] Examples for {filename}: "
+        + (", ".join(f"{name} ({score:.1f})" for score, name, _ in selected) or "none")
+    )
+    return "".join(text for _, _, text in selected)
+
+
+def get_changed_files(repo, base_branch, head_branch, path_filters, fetch=True):
+    # Local reviews diff existing revisions as-is, without touching the checkout
+    if fetch:
+        origin = repo.remotes.origin
+        print("[DEBUG] Fetching all branches...")
+        origin.fetch()
+
+        if base_branch not in repo.heads:
+            print(f"[DEBUG] Base branch {base_branch} not found locally. Creating it.")
+            repo.create_head(base_branch, origin.refs[base_branch])
+        else:
+            repo.heads[base_branch].set_tracking_branch(origin.refs[base_branch])
+
+        print(f"[DEBUG] Checking out head branch: {head_branch}")
+        repo.git.checkout(head_branch)
 
     base_commit = repo.merge_base(base_branch, head_branch)
     if not base_commit:
@@ -58,47 +465,1670 @@ def get_changed_files(repo, base_branch, head_branch, file_extensions):
     print(f"[DEBUG] Found common ancestor: {base_commit[0].hexsha}")
 
     diff_index = base_commit[0].diff(head_branch, create_patch=True)
+    return filter_diffs(diff_index, path_filters)
+
+
+def get_incremental_diffs(repo, since_sha, head_branch, path_filters):
+    try:
+        since_commit = repo.commit(since_sha)
+        is_ancestor = repo.is_ancestor(since_commit, head_branch)
+    except Exception as e:
+        print(f"[DEBUG] Last reviewed commit {since_sha} not available: {e}")
+        return None
+    if not is_ancestor:
+        # Typically a force-push rewrote the history since the last review
+        print(f"[DEBUG] Last reviewed commit {since_sha} is not an ancestor of head")
+        return None
+
+    print(f"[DEBUG] Diffing since last reviewed commit: {since_commit.hexsha}")
+    diff_index = since_commit.diff(head_branch, create_patch=True)
+    return filter_diffs(diff_index, path_filters)
+
+
+DEFAULT_EXCLUDES = [
+    # Lockfiles
+    "Cargo.lock",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "poetry.lock",
+    "Pipfile.lock",
+    "Gemfile.lock",
+    "composer.lock",
+    "go.sum",
+    # Binaries and other non-reviewable artifacts
+    "*.png",
+    "*.jpg",
+    "*.jpeg",
+    "*.gif",
+    "*.ico",
+    "*.pdf",
+    "*.zip",
+    "*.gz",
+    "*.tar",
+    "*.jar",
+    "*.exe",
+    "*.dll",
+    "*.so",
+    "*.dylib",
+    "*.a",
+    "*.rlib",
+    "*.wasm",
+    "*.woff",
+    "*.woff2",
+    "*.ttf",
+]
+
+
+def glob_to_regex(pattern):
+    # gitignore semantics: a pattern without a slash matches the name at any depth,
+    # a leading slash anchors it to the repository root, and a trailing slash
+    # matches everything below a directory
+    anchored = "/" in pattern.rstrip("/")
+    pattern = pattern.lstrip("/")
+    if pattern.endswith("/"):
+        pattern += "**"
+
+    regex = ""
+    i = 0
+    while i < len(pattern):
+        if pattern.startswith("**/", i):
+            regex += "(?:.*/)?"
+            i += 3
+        elif pattern.startswith("**", i):
+            regex += ".*"
+            i += 2
+        elif pattern[i] == "*":
+            regex += "[^/]*"
+            i += 1
+        elif pattern[i] == "?":
+            regex += "[^/]"
+            i += 1
+        elif pattern[i] == "[":
+            end = pattern.find("]", i + 1)
+            if end == -1:
+                regex += re.escape(pattern[i])
+                i += 1
+            else:
+                char_class = pattern[i + 1 : end]
+                if char_class.startswith("!"):
+                    char_class = "^" + char_class[1:]
+                regex += f"[{char_class}]"
+                i = end + 1
+        else:
+            regex += re.escape(pattern[i])
+            i += 1
+
+    prefix = "" if anchored else "(?:.*/)?"
+    # Matching a directory also matches everything below it
+    return re.compile(f"^{prefix}{regex}(?:/.*)?$")
+
+
+DEFAULT_EXCLUDE_REGEXES = [glob_to_regex(pattern) for pattern in DEFAULT_EXCLUDES]
+
+
+def build_path_filters(file_extensions=(), path_patterns=()):
+    patterns = [f"*{ext}" for ext in file_extensions]
+    patterns += [p.strip() for p in path_patterns if p.strip()]
+    path_filters = []
+    for pattern in patterns:
+        negated = pattern.startswith("!")
+        glob = pattern[1:] if negated else pattern
+        path_filters.append((negated, glob_to_regex(glob), pattern))
+    return path_filters
+
+
+def parse_path_patterns(value):
+    return [p.strip() for p in re.split(r"[,\n]", value) if p.strip()]
+
+
+def is_path_included(file_path, path_filters):
+    # With no positive patterns every file is a candidate; as in .gitignore the
+    # last matching pattern decides
+    included = not any(not negated for negated, _, _ in path_filters)
+    if any(regex.match(file_path) for regex in DEFAULT_EXCLUDE_REGEXES):
+        included = False
+    for negated, regex, _ in path_filters:
+        if regex.match(file_path):
+            included = not negated
+    return included
 
-    print("[DEBUG] Filtering diffs by file extensions:", file_extensions)
+
+def get_diff_path(diff):
+    # Renamed files are reviewed under their new path, deleted ones only have the old
+    if diff.deleted_file or not diff.b_path:
+        return diff.a_path
+    return diff.b_path
+
+
+def classify_diff(diff):
+    if diff.deleted_file:
+        return "deleted"
+    patch = diff.diff or b""
+    if patch.startswith(b"Binary files") or b"\0" in patch:
+        return "binary"
+    try:
+        patch.decode("utf-8")
+    except UnicodeDecodeError:
+        return "binary"
+    if diff.new_file:
+        return "added"
+    if diff.renamed_file:
+        return "renamed"
+    return "modified"
+
+
+def filter_diffs(diff_index, path_filters):
+    print("[DEBUG] Filtering diffs by paths:", [p for _, _, p in path_filters])
     filtered_diffs = []
     for diff in diff_index:
-        if diff.a_path:
-            file_path = diff.a_path
-        else:
-            file_path = diff.b_path
+        file_path = get_diff_path(diff)
 
-        if any(file_path.endswith(ext) for ext in file_extensions):
+        if is_path_included(file_path, path_filters):
             print(f"[DEBUG] Including diff for file: {file_path}")
             filtered_diffs.append(diff)
         else:
-            print(f"[DEBUG] Skipping file not matching extensions: {file_path}")
+            print(f"[DEBUG] Skipping file not matching path filters: {file_path}")
 
     return filtered_diffs
 
 
-def review_code_with_llm(
-    filename, diff_content, manual_content, example_contents, api_key
+HUNK_HEADER_RE = re.compile(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@(.*)$")
+
+# Rough overhead of the prompt template itself, excluding manual, examples and diff
+PROMPT_TEMPLATE_TOKENS = 400
+MIN_DIFF_TOKEN_BUDGET = 500
+
+
+def estimate_tokens(text):
+    # Roughly four characters per token for code and English prose
+    return len(text) // 4 + 1
+
+
+def split_diff_into_hunks(diff_content):
+    hunks = []
+    current = []
+    for line in diff_content.split("\n"):
+        if line.startswith("@@") and current:
+            hunks.append("\n".join(current))
+            current = []
+        current.append(line)
+    if current:
+        hunks.append("\n".join(current))
+    return hunks
+
+
+def split_large_hunk(hunk, token_budget):
+    lines = hunk.split("\n")
+    match = HUNK_HEADER_RE.match(lines[0])
+    if not match:
+        # Preamble without a hunk header, split on plain line boundaries
+        pieces, current = [], []
+        for line in lines:
+            if current and estimate_tokens("\n".join(current + [line])) > token_budget:
+                pieces.append("\n".join(current))
+                current = []
+            current.append(line)
+        if current:
+            pieces.append("\n".join(current))
+        return pieces
+
+    old_line = int(match.group(1))
+    new_line = int(match.group(3))
+    section = match.group(5)
+
+    pieces = []
+    body = []
+    old_start, new_start = old_line, new_line
+    old_count = new_count = 0
+
+    def flush():
+        header = f"@@ -{old_start},{old_count} +{new_start},{new_count} @@{section}"
+        pieces.append("\n".join([header] + body))
+
+    for line in lines[1:]:
+        if body and estimate_tokens("\n".join(body + [line])) > token_budget:
+            flush()
+            body = []
+            old_start, new_start = old_line, new_line
+            old_count = new_count = 0
+
+        body.append(line)
+        if line.startswith("+"):
+            new_line += 1
+            new_count += 1
+        elif line.startswith("-"):
+            old_line += 1
+            old_count += 1
+        elif not line.startswith("\\"):
+            old_line += 1
+            new_line += 1
+            old_count += 1
+            new_count += 1
+
+    if body:
+        flush()
+    return pieces
+
+
+def chunk_diff(diff_content, token_budget):
+    if estimate_tokens(diff_content) <= token_budget:
+        return [diff_content]
+
+    chunks = []
+    current = []
+    for hunk in split_diff_into_hunks(diff_content):
+        if estimate_tokens(hunk) > token_budget:
+            pieces = split_large_hunk(hunk, token_budget)
+        else:
+            pieces = [hunk]
+
+        for piece in pieces:
+            candidate = "\n".join(current + [piece])
+            if current and estimate_tokens(candidate) > token_budget:
+                chunks.append("\n".join(current))
+                current = []
+            current.append(piece)
+
+    if current:
+        chunks.append("\n".join(current))
+    return chunks
+
+
+def get_largest_manual(file_contexts):
+    # Prompts are budgeted for the largest manual any file is reviewed with
+    return max(
+        (context["manual_content"] for context in file_contexts.values()),
+        key=len,
+        default="",
+    )
+
+
+def get_diff_token_budget(max_prompt_tokens, manual_content, example_tokens):
+    # The prompt carries the manual both in full and as its rule catalogue
+    catalogue = format_rule_catalogue(parse_rule_catalogue(manual_content))
+    context_tokens = (
+        estimate_tokens(manual_content)
+        + estimate_tokens(catalogue)
+        + example_tokens
+        + PROMPT_TEMPLATE_TOKENS
+    )
+    budget = max_prompt_tokens - context_tokens
+    print(
+        f"[DEBUG] Prompt budget: {max_prompt_tokens} tokens, context uses ~{context_tokens}, leaving ~{budget} for the diff"
+    )
+    if budget < MIN_DIFF_TOKEN_BUDGET:
+        print(
+            f"Warning: manual and examples leave too little room for the diff, using {MIN_DIFF_TOKEN_BUDGET} tokens per chunk"
+        )
+        budget = MIN_DIFF_TOKEN_BUDGET
+    return budget
+
+
+RUST_ITEM_RE = re.compile(
+    r"^\s*(?:pub(?:\([^)]*\))?\s+)?"
+    r"(?:(?:const|async|unsafe|default|extern\s+\"[^\"]*\")\s+)*"
+    r"(?:fn|impl|struct|enum|trait|mod|union|macro_rules!)\b"
+)
+RUST_ITEM_PREAMBLE_RE = re.compile(r"^\s*(?:///|//!|#\[|#!\[)")
+RUST_STRIP_RE = re.compile(r'//.*$|"(?:\\.|[^"\\])*"|\'(?:\\.|[^\'\\])\'')
+
+
+def find_rust_item_end(lines, start_idx):
+    depth = 0
+    seen_open = False
+    for idx in range(start_idx, len(lines)):
+        # Braces inside comments, strings and char literals don't count
+        code = RUST_STRIP_RE.sub("", lines[idx])
+        depth += code.count("{") - code.count("}")
+        seen_open = seen_open or "{" in code
+        if seen_open and depth <= 0:
+            return idx
+        if not seen_open and code.rstrip().endswith(";"):
+            # Unit/tuple structs, `mod foo;` and trait method declarations
+            return idx
+    return len(lines) - 1
+
+
+def find_enclosing_rust_items(file_lines, line_numbers):
+    items = [
+        (idx, find_rust_item_end(file_lines, idx))
+        for idx, line in enumerate(file_lines)
+        if RUST_ITEM_RE.match(line)
+    ]
+    selected = set()
+    for line_no in line_numbers:
+        idx = line_no - 1
+        enclosing = [item for item in items if item[0] <= idx <= item[1]]
+        if enclosing:
+            # Innermost item, e.g. the fn rather than the impl around it
+            selected.add(min(enclosing, key=lambda item: item[1] - item[0]))
+
+    ranges = []
+    for start, end in sorted(selected):
+        # Include doc comments and attributes above the item
+        while start > 0 and RUST_ITEM_PREAMBLE_RE.match(file_lines[start - 1]):
+            start -= 1
+        ranges.append((start + 1, end + 1))
+    return merge_line_ranges(ranges)
+
+
+def merge_line_ranges(ranges):
+    merged = []
+    for start, end in sorted(ranges):
+        if merged and start <= merged[-1][1] + 1:
+            merged[-1] = (merged[-1][0], max(merged[-1][1], end))
+        else:
+            merged.append((start, end))
+    return merged
+
+
+def format_line_ranges(file_lines, ranges):
+    blocks = []
+    for start, end in ranges:
+        blocks.append(
+            "\n".join(
+                f"{line_no:>5} | {file_lines[line_no - 1]}"
+                for line_no in range(start, min(end, len(file_lines)) + 1)
+            )
+        )
+    return "\n   ...\n".join(blocks)
+
+
+def build_file_context(filename, head_content, diff_content, options, token_budget):
+    if head_content is None or not options:
+        return ""
+    file_lines = head_content.split("\n")
+
+    full_file_max_lines = options.get("full_file_max_lines", 0)
+    if 0 < len(file_lines) <= full_file_max_lines:
+        full_file = format_line_ranges(file_lines, [(1, len(file_lines))])
+        if estimate_tokens(full_file) <= token_budget:
+            return f"Full file {filename} at the head commit:\n{full_file}"
+
+    hunk_lines = [hunk["RIGHT"] for hunk in parse_diff_hunks(diff_content)]
+    hunk_lines = [lines for lines in hunk_lines if lines]
+    sections = []
+
+    if options.get("rust_items") and filename.endswith(".rs"):
+        changed_lines = set().union(*hunk_lines) if hunk_lines else set()
+        item_ranges = find_enclosing_rust_items(file_lines, changed_lines)
+        if item_ranges:
+            sections.append(
+                "Enclosing items of the changed lines:\n"
+                + format_line_ranges(file_lines, item_ranges)
+            )
+
+    context_lines = options.get("context_lines", 0)
+    if context_lines > 0 and hunk_lines:
+        around_ranges = merge_line_ranges(
+            (max(1, min(lines) - context_lines), max(lines) + context_lines)
+            for lines in hunk_lines
+        )
+        sections.append(
+            f"{context_lines} lines around each change:\n"
+            + format_line_ranges(file_lines, around_ranges)
+        )
+
+    context = "\n\n".join(sections)
+    max_chars = token_budget * 4
+    if len(context) > max_chars:
+        context = context[:max_chars] + "\n   ... (context truncated)"
+    return context
+
+
+def get_context_options():
+    options = {
+        "context_lines": int(get_input("context_lines", "10")),
+        "full_file_max_lines": int(get_input("full_file_max_lines", "300")),
+        "rust_items": get_input("rust_enclosing_items", "true").lower() == "true",
+    }
+    return options if any(options.values()) else None
+
+
+def read_head_files(repo, head, filenames):
+    head_contents = {}
+    for filename in filenames:
+        try:
+            head_contents[filename] = repo.git.show(f"{head}:{filename}")
+        except Exception as e:
+            print(f"[DEBUG] Could not read {filename} at {head}: {e}")
+            head_contents[filename] = None
+    return head_contents
+
+
+# How each automated check finds the manual rule it enforces: by the rule's
+# wording, preferring rules in a matching section, so manuals with their own
+# IDs are cited too. The limit is the first number of the rule, else the default
+AUTOMATED_CHECK_RULES = {
+    "file_length": (
+        r"organi[sz]ation|structure|style",
+        r"\bfile (?:length|size)\b|\blines? per file\b",
+        500,
+    ),
+    "fn_length": (
+        r"organi[sz]ation|structure|style",
+        r"\bfunction (?:length|size)\b|\blines? per function\b",
+        50,
+    ),
+    "line_length": (
+        r"organi[sz]ation|structure|style|format",
+        r"\bline (?:length|width)\b|\bcharacters per line\b",
+        100,
+    ),
+    "const_naming": (r"naming", r"\bconstants?\b.*\bSCREAMING_SNAKE_CASE\b", None),
+    "type_naming": (r"naming", r"\btypes?\b.*\bPascalCase\b", None),
+    "clippy": (r"tool|lint", r"\bclippy\b", None),
+    "rustfmt": (r"tool|format|review", r"\bcargo fmt\b|\brustfmt\b", None),
+}
+RUST_FN_RE = re.compile(
+    r"^\s*(?:pub(?:\([^)]*\))?\s+)?"
+    r"(?:(?:const|async|unsafe|default|extern\s+\"[^\"]*\")\s+)*"
+    r"fn\s+(\w+)"
+)
+RUST_CONST_RE = re.compile(
+    r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:const|static(?:\s+mut)?)\s+(\w+)\s*:"
+)
+RUST_TYPE_RE = re.compile(
+    r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:unsafe\s+)?"
+    r"(struct|enum|trait|union|type)\s+(\w+)"
+)
+SCREAMING_SNAKE_CASE_RE = re.compile(r"^[A-Z][A-Z0-9]*(?:_[A-Z0-9]+)*$")
+PASCAL_CASE_RE = re.compile(r"^[A-Z][A-Za-z0-9]*$")
+
+
+def get_added_lines(diff):
+    added_lines = []
+    new_line = None
+    for line in diff.split("\n"):
+        match = HUNK_HEADER_RE.match(line)
+        if match:
+            new_line = int(match.group(3))
+        elif new_line is None:
+            continue
+        elif line.startswith("+"):
+            added_lines.append(new_line)
+            new_line += 1
+        elif line.startswith(" "):
+            new_line += 1
+    return added_lines
+
+
+def make_finding(filename, line, rule_id, category, comment, severity="warning"):
+    return {
+        "filename": filename,
+        "line": line,
+        "start_line": None,
+        "side": "RIGHT",
+        "comment": comment,
+        "suggestion": None,
+        "severity": severity,
+        "category": category,
+        "rule_id": rule_id,
+        "automated": True,
+    }
+
+
+def get_automated_check_rule(rules, check):
+    # Cite the manual's rule and honor its limit when the manual has one
+    section_pattern, text_pattern, default_limit = AUTOMATED_CHECK_RULES[check]
+    matches = [
+        rule for rule in rules if re.search(text_pattern, rule["text"], re.IGNORECASE)
+    ]
+    if not matches:
+        return GENERAL_RULE_ID, default_limit
+    rule = min(
+        matches,
+        key=lambda rule: not re.search(section_pattern, rule["section"], re.IGNORECASE),
+    )
+    number = re.search(r"\d+", rule["text"])
+    return rule["id"], int(number.group()) if number else default_limit
+
+
+def to_screaming_snake_case(name):
+    return re.sub(r"(?<=[a-z0-9])(?=[A-Z])", "_", name).upper()
+
+
+def to_pascal_case(name):
+    return "".join(part[:1].upper() + part[1:] for part in name.split("_") if part)
+
+
+def run_pre_checks(filename, head_content, diff_content, rules):
+    if head_content is None or not filename.endswith(".rs"):
+        return []
+    added_lines = get_added_lines(diff_content)
+    if not added_lines:
+        return []
+    file_lines = head_content.split("\n")
+    if file_lines and file_lines[-1] == "":
+        file_lines = file_lines[:-1]
+
+    findings = []
+
+    def add_finding(line, rule_id, category, comment):
+        findings.append(make_finding(filename, line, rule_id, category, comment))
+
+    rule_id, limit = get_automated_check_rule(rules, "file_length")
+    if len(file_lines) > limit:
+        # Point at the first added line past the limit, the change that
+        # pushed the file over it
+        over_limit = [line for line in added_lines if line > limit]
+        add_finding(
+            over_limit[0] if over_limit else added_lines[-1],
+            rule_id,
+            "organization",
+            f"This file has {len(file_lines)} lines, over the limit of {limit}. "
+            "Consider splitting it into smaller modules.",
+        )
+
+    rule_id, limit = get_automated_check_rule(rules, "fn_length")
+    diff_lines = get_new_side_lines(diff_content)
+    for idx, line in enumerate(file_lines):
+        fn = RUST_FN_RE.match(line)
+        if not fn:
+            continue
+        end = find_rust_item_end(file_lines, idx)
+        length = end - idx + 1
+        changed = [n for n in added_lines if idx + 1 <= n <= end + 1]
+        if length <= limit or not changed:
+            continue
+        add_finding(
+            idx + 1 if idx + 1 in diff_lines else changed[0],
+            rule_id,
+            "organization",
+            f"Function `{fn.group(1)}` is {length} lines long, over the limit of "
+            f"{limit}. Consider extracting parts of it into helper functions.",
+        )
+
+    rule_id, limit = get_automated_check_rule(rules, "line_length")
+    for line_no in added_lines:
+        length = len(file_lines[line_no - 1]) if line_no <= len(file_lines) else 0
+        if length > limit:
+            add_finding(
+                line_no,
+                rule_id,
+                "organization",
+                f"This line is {length} characters long, over the limit of {limit}.",
+            )
+
+    const_rule_id, _ = get_automated_check_rule(rules, "const_naming")
+    type_rule_id, _ = get_automated_check_rule(rules, "type_naming")
+    for line_no in added_lines:
+        if line_no > len(file_lines):
+            continue
+        line = file_lines[line_no - 1]
+        const = RUST_CONST_RE.match(line)
+        if (
+            const
+            and const.group(1) != "_"
+            and not SCREAMING_SNAKE_CASE_RE.match(const.group(1))
+        ):
+            add_finding(
+                line_no,
+                const_rule_id,
+                "naming",
+                f"Constant `{const.group(1)}` should be SCREAMING_SNAKE_CASE, "
+                f"e.g. `{to_screaming_snake_case(const.group(1))}`.",
+            )
+        item = RUST_TYPE_RE.match(line)
+        if item and not PASCAL_CASE_RE.match(item.group(2)):
+            add_finding(
+                line_no,
+                type_rule_id,
+                "naming",
+                f"The {item.group(1)} `{item.group(2)}` should be PascalCase, "
+                f"e.g. `{to_pascal_case(item.group(2))}`.",
+            )
+
+    return sorted(findings, key=lambda finding: finding["line"])
+
+
+def format_automated_findings(findings):
+    return "\n".join(
+        f"- line {finding['line']} [{finding['rule_id']}]: {finding['comment']}"
+        for finding in findings
+    )
+
+
+CARGO_TIMEOUT_SECONDS = 900
+# Denied and forbidden lints are still passed as warnings, so no lint stops
+# cargo before it checks the crates depending on the offending one; the
+# configured level sets the finding's severity instead
+LINT_SEVERITIES = {"warn": "warning", "deny": "error", "forbid": "error"}
+LINT_IMPLIED_BY_RE = re.compile(r"implied by `-[AWDF] ([\w:-]+)`")
+RUSTC_ERROR_CODE_RE = re.compile(r"^E\d{4}$")
+TOML_BLOCK_RE = re.compile(r"^```toml\s*$(.*?)^```", re.MULTILINE | re.DOTALL)
+TOML_LINT_RE = re.compile(
+    r'^([\w-]+)\s*=\s*(?:"(\w+)"|\{.*\blevel\s*=\s*"(\w+)".*\})$'
+)
+RUSTFMT_DIFF_RE = re.compile(r"^Diff in (.+?)(?: at line |:)(\d+):\s*$")
+CLIPPY_SEVERITIES = {"error": "error", "warning": "warning"}
+# The only variables cargo gets. This keeps secrets out of build logs, but it
+# is no sandbox: build scripts and proc-macros of the PR run in this container
+# and can still read them from the action process, e.g. via /proc/1/environ
+CARGO_ENV_VARS = ("PATH", "HOME", "CARGO_HOME", "RUSTUP_HOME")
+# Installed on demand, so images of runs without cargo_checks stay small
+RUST_INSTALL_COMMANDS = (
+    ["apt-get", "update"],
+    [
+        "apt-get",
+        "install",
+        "-y",
+        "--no-install-recommends",
+        "ca-certificates",
+        "curl",
+        "gcc",
+        "libc6-dev",
+    ],
+    [
+        "sh",
+        "-c",
+        "curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y "
+        "--profile minimal --component clippy,rustfmt --no-modify-path",
+    ],
+)
+
+
+def get_manual_lint_levels(manual_content):
+    # The [lints.rust] and [lints.clippy] tables of the manual's TOML snippets,
+    # e.g. pedantic = "warn" -> {"clippy::pedantic": "warn"}
+    lint_levels = {}
+    for block in TOML_BLOCK_RE.findall(manual_content):
+        table = None
+        for line in block.splitlines():
+            line = line.split("#", 1)[0].strip()
+            header = re.match(r"^\[(.+)\]$", line)
+            if header:
+                table = header.group(1).strip()
+                continue
+            entry = TOML_LINT_RE.match(line)
+            if not entry or table not in ("lints.rust", "lints.clippy"):
+                continue
+            level = entry.group(2) or entry.group(3)
+            if level != "allow" and level not in LINT_SEVERITIES:
+                continue
+            lint = entry.group(1).replace("-", "_")
+            if table == "lints.clippy":
+                lint = f"clippy::{lint}"
+            lint_levels[lint] = level
+    return lint_levels
+
+
+def get_manual_lint_flags(lint_levels):
+    flags = []
+    for lint, level in lint_levels.items():
+        flags += ["-A" if level == "allow" else "-W", lint]
+    return flags
+
+
+def get_lint_severity(diagnostic, lint_levels, lint_groups):
+    code = (diagnostic.get("code") or {}).get("code")
+    if not code or RUSTC_ERROR_CODE_RE.match(code):
+        # Compiler errors, and warnings that aren't lints
+        return CLIPPY_SEVERITIES[diagnostic["level"]]
+    lint = code.replace("-", "_")
+    # Only the first diagnostic of a lint names the group that enabled it
+    for child in diagnostic.get("children", []):
+        implied_by = LINT_IMPLIED_BY_RE.search(child["message"])
+        if implied_by:
+            lint_groups[lint] = implied_by.group(1).replace("-", "_")
+    level = lint_levels.get(lint) or lint_levels.get(lint_groups.get(lint))
+    return LINT_SEVERITIES.get(level, "warning")
+
+
+def get_cargo_env():
+    return {name: os.environ[name] for name in CARGO_ENV_VARS if name in os.environ}
+
+
+def ensure_rust_toolchain():
+    if shutil.which("cargo"):
+        return True
+    print("[DEBUG] Installing a Rust toolchain for cargo checks...")
+    for command in RUST_INSTALL_COMMANDS:
+        try:
+            subprocess.run(command, check=True, timeout=CARGO_TIMEOUT_SECONDS)
+        except (OSError, subprocess.SubprocessError) as e:
+            print(f"::warning::Could not install a Rust toolchain: {e}")
+            return False
+    return shutil.which("cargo") is not None
+
+
+def run_cargo(args, repo_path):
+    print(f"[DEBUG] Running cargo {' '.join(args)}")
+    try:
+        # Both commands report findings through a non-zero exit code, so the
+        # output is parsed regardless of it
+        return subprocess.run(
+            ["cargo"] + args,
+            cwd=repo_path,
+            capture_output=True,
+            text=True,
+            timeout=CARGO_TIMEOUT_SECONDS,
+            env=get_cargo_env(),
+        ).stdout
+    except (OSError, subprocess.TimeoutExpired) as e:
+        print(f"::warning::cargo {args[0]} did not complete: {e}")
+        return None
+
+
+def get_repo_relative_path(path, repo_path):
+    path = os.path.normpath(path)
+    if os.path.isabs(path):
+        path = os.path.relpath(path, repo_path)
+    # Diagnostics in dependencies or the standard library
+    return None if path.startswith("..") else path
+
+
+def apply_clippy_suggestion(diagnostic, line_no, head_content):
+    if head_content is None:
+        return None
+    for child in diagnostic.get("children", []):
+        spans = [
+            span
+            for span in child.get("spans", [])
+            if span.get("suggested_replacement") is not None
+        ]
+        # Only a single replacement within the commented line maps onto a
+        # suggestion block
+        if len(spans) != 1:
+            continue
+        span = spans[0]
+        if span["line_start"] != line_no or span["line_end"] != line_no:
+            continue
+        head_lines = head_content.split("\n")
+        if line_no > len(head_lines):
+            return None
+        line = head_lines[line_no - 1]
+        return (
+            line[: span["column_start"] - 1]
+            + span["suggested_replacement"]
+            + line[span["column_end"] - 1 :]
+        )
+    return None
+
+
+def parse_clippy_output(output, repo_path, rule_id, head_contents, lint_levels=None):
+    findings = []
+    seen = set()
+    lint_groups = {}
+    for line in output.splitlines():
+        try:
+            message = json.loads(line)
+        except ValueError:
+            continue
+        if message.get("reason") != "compiler-message":
+            continue
+        diagnostic = message["message"]
+        spans = [span for span in diagnostic.get("spans", []) if span["is_primary"]]
+        if diagnostic.get("level") not in CLIPPY_SEVERITIES or not spans:
+            continue
+        severity = get_lint_severity(diagnostic, lint_levels or {}, lint_groups)
+        filename = get_repo_relative_path(spans[0]["file_name"], repo_path)
+        line_no = spans[0]["line_start"]
+        code = (diagnostic.get("code") or {}).get("code")
+        # --all-targets reports the same diagnostic for every target
+        key = (filename, line_no, code, diagnostic["message"])
+        if filename is None or key in seen:
+            continue
+        seen.add(key)
+
+        comment = diagnostic["message"]
+        if code:
+            comment = f"`{code}`: {comment}"
+        help_lines = [
+            child["message"]
+            for child in diagnostic.get("children", [])
+            if child.get("level") == "help" and not child.get("spans")
+        ]
+        if help_lines:
+            comment += "\n\n" + "\n".join(help_lines)
+        finding = make_finding(
+            filename, line_no, rule_id, "tooling", comment, severity=severity
+        )
+        finding["suggestion"] = apply_clippy_suggestion(
+            diagnostic, line_no, head_contents.get(filename)
+        )
+        findings.append(finding)
+    return findings
+
+
+def parse_rustfmt_output(output, repo_path, rule_id):
+    findings = []
+    filename = None
+    old_line = 0
+    previous = None
+    change = None
+
+    def flush():
+        # Pure insertions replace the line above them together with it
+        if change and change["start"] is None and previous:
+            change["start"] = change["end"] = previous[0]
+            change["lines"].insert(0, previous[1])
+        if change and change["start"] is not None:
+            finding = make_finding(
+                filename,
+                change["end"],
+                rule_id,
+                "tooling",
+                "This code is not formatted according to rustfmt, run `cargo fmt`.",
+                severity="info",
+            )
+            if change["end"] > change["start"]:
+                finding["start_line"] = change["start"]
+            finding["suggestion"] = "\n".join(change["lines"])
+            findings.append(finding)
+
+    for line in output.splitlines():
+        header = RUSTFMT_DIFF_RE.match(line)
+        if header:
+            flush()
+            filename = get_repo_relative_path(header.group(1), repo_path)
+            old_line = int(header.group(2))
+            previous = change = None
+            continue
+        if filename is None:
+            continue
+        if line.startswith("-"):
+            change = change or {"start": None, "end": None, "lines": []}
+            change["start"] = change["start"] or old_line
+            change["end"] = old_line
+            old_line += 1
+        elif line.startswith("+"):
+            change = change or {"start": None, "end": None, "lines": []}
+            change["lines"].append(line[1:])
+        else:
+            flush()
+            change = None
+            if line.startswith(" "):
+                previous = (old_line, line[1:])
+                old_line += 1
+    flush()
+    return findings
+
+
+def run_cargo_checks(repo_path, diffs_by_file, head_contents, manual_content):
+    if not os.path.exists(os.path.join(repo_path, "Cargo.toml")):
+        print("[DEBUG] No Cargo.toml at the repository root, skipping cargo checks")
+        return {}
+    if not ensure_rust_toolchain():
+        print("::warning::cargo is not available, skipping cargo checks")
+        return {}
+    rules = parse_rule_catalogue(manual_content)
+    lint_levels = get_manual_lint_levels(manual_content)
+    findings = []
+
+    clippy_output = run_cargo(
+        ["clippy", "--workspace", "--all-targets", "--message-format=json", "--"]
+        + get_manual_lint_flags(lint_levels),
+        repo_path,
+    )
+    if clippy_output is not None:
+        findings += parse_clippy_output(
+            clippy_output,
+            repo_path,
+            get_automated_check_rule(rules, "clippy")[0],
+            head_contents,
+            lint_levels,
+        )
+    fmt_output = run_cargo(
+        ["fmt", "--all", "--", "--check", "--color", "never"], repo_path
+    )
+    if fmt_output is not None:
+        findings += parse_rustfmt_output(
+            fmt_output, repo_path, get_automated_check_rule(rules, "rustfmt")[0]
+        )
+
+    # Only diagnostics on lines the pull request changes are reported
+    findings_by_file = {}
+    for finding in findings:
+        diff_content = diffs_by_file.get(finding["filename"])
+        if diff_content is None:
+            continue
+        added_lines = set(get_added_lines(diff_content))
+        first_line = finding["start_line"] or finding["line"]
+        if added_lines.intersection(range(first_line, finding["line"] + 1)):
+            findings_by_file.setdefault(finding["filename"], []).append(finding)
+    print(
+        f"[DEBUG] Cargo checks reported {sum(map(len, findings_by_file.values()))} findings on changed lines"
+    )
+    return findings_by_file
+
+
+def get_input(name, default=""):
+    # Docker actions receive every input as an INPUT_<NAME> environment variable,
+    # which takes precedence over the repository config file
+    value = os.environ.get(f"INPUT_{name.upper()}", "").strip()
+    return value if value else REPO_CONFIG.get(name, default)
+
+
+def set_output(name, value):
+    output_path = os.environ.get("GITHUB_OUTPUT")
+    if not output_path:
+        print(f"[DEBUG] GITHUB_OUTPUT not set, output {name}={value}")
+        return
+    with open(output_path, "a") as f:
+        # Heredoc syntax so multi-line values are preserved
+        delimiter = f"EOF_{random.getrandbits(64):x}"
+        f.write(f"{name}<<{delimiter}\n{value}\n{delimiter}\n")
+
+
+CONFIG_FILE = ".llm-review.toml"
+# Settings of the repository config file, named like the action inputs they
+# provide values for
+CONFIG_SETTINGS = {
+    "llm_provider": str,
+    "llm_model": str,
+    "llm_base_url": str,
+    "paths": list,
+    "manual_path": str,
+    "examples_path": str,
+    "fail_on": str,
+    "min_severity": str,
+    "max_comments": int,
+    "prompt_additions": str,
+}
+# Settings an [[overrides]] table may change for the files matching its paths
+OVERRIDE_SETTINGS = ("fail_on", "min_severity", "prompt_additions")
+# Values of the loaded config file, as input strings
+REPO_CONFIG = {}
+
+
+def validate_config_settings(values, where, allowed):
+    settings = {}
+    for key, value in values.items():
+        if key not in allowed:
+            raise ValueError(
+                f"{where}: unknown setting '{key}', expected one of {', '.join(allowed)}"
+            )
+        expected = CONFIG_SETTINGS[key]
+        if expected is int:
+            if isinstance(value, bool) or not isinstance(value, int) or value < 0:
+                raise ValueError(
+                    f"{where}: '{key}' must be a non-negative integer, got {value!r}"
+                )
+        elif expected is list:
+            if not isinstance(value, list) or not all(
+                isinstance(item, str) for item in value
+            ):
+                raise ValueError(
+                    f"{where}: '{key}' must be a list of strings, got {value!r}"
+                )
+        elif not isinstance(value, str):
+            raise ValueError(f"{where}: '{key}' must be a string, got {value!r}")
+
+        choices = {
+            "llm_provider": tuple(PROVIDERS),
+            "fail_on": ("none", *SEVERITIES),
+            "min_severity": SEVERITIES,
+        }.get(key)
+        if choices and value not in choices:
+            raise ValueError(
+                f"{where}: '{key}' must be one of {', '.join(choices)}, got {value!r}"
+            )
+        settings[key] = "\n".join(value) if expected is list else str(value)
+    return settings
+
+
+def load_repo_config(config_text, source):
+    if config_text is None:
+        print(f"[DEBUG] No repository config found at {source}")
+        return {}, []
+    try:
+        data = tomllib.loads(config_text)
+    except tomllib.TOMLDecodeError as e:
+        raise ValueError(f"{source}: invalid TOML: {e}")
+
+    data = dict(data)
+    overrides = data.pop("overrides", [])
+    settings = validate_config_settings(data, source, tuple(CONFIG_SETTINGS))
+    if not isinstance(overrides, list):
+        raise ValueError(f"{source}: 'overrides' must be an array of tables")
+
+    path_overrides = []
+    for idx, override in enumerate(overrides):
+        where = f"{source}: overrides[{idx}]"
+        if not isinstance(override, dict):
+            raise ValueError(f"{where} must be a table")
+        override = dict(override)
+        paths = override.pop("paths", None)
+        if not paths or not isinstance(paths, list):
+            raise ValueError(f"{where}: 'paths' must be a non-empty list of globs")
+        validate_config_settings({"paths": paths}, where, ("paths",))
+        path_override = validate_config_settings(override, where, OVERRIDE_SETTINGS)
+        path_override["path_filters"] = build_path_filters([], paths)
+        path_overrides.append(path_override)
+
+    print(
+        f"[DEBUG] Loaded {source}: {', '.join(settings) or 'no settings'}, {len(path_overrides)} overrides"
+    )
+    return settings, path_overrides
+
+
+def read_config_file(config_path):
+    if not os.path.exists(config_path):
+        return None
+    with open(config_path, "r") as f:
+        return f.read()
+
+
+def read_base_branch_config(repo, base_ref, config_file):
+    # The pull request being reviewed must not be able to loosen its own gate,
+    # e.g. by setting fail_on = "none", so its base branch has the say
+    try:
+        repo.remotes.origin.fetch(base_ref)
+        return repo.git.show(f"origin/{base_ref}:{config_file}")
+    except GitCommandError as e:
+        print(f"[DEBUG] Could not read {config_file} from {base_ref}: {e}")
+        return None
+
+
+def apply_repo_config(config_text, source):
+    settings, overrides = load_repo_config(config_text, source)
+    REPO_CONFIG.update(settings)
+    return overrides
+
+
+def matches_path_filters(file_path, path_filters):
+    matched = False
+    for negated, regex, _ in path_filters:
+        if regex.match(file_path):
+            matched = not negated
+    return matched
+
+
+def get_path_setting(overrides, file_path, name, default):
+    # As with path filters, the last matching override wins
+    value = default
+    for override in overrides:
+        if name in override and matches_path_filters(
+            file_path, override["path_filters"]
+        ):
+            value = override[name]
+    return value
+
+
+def get_prompt_additions(prompt_additions, overrides, file_path):
+    # Unlike other settings, path-specific instructions add to the global ones
+    parts = [prompt_additions] + [
+        override["prompt_additions"]
+        for override in overrides
+        if "prompt_additions" in override
+        and matches_path_filters(file_path, override["path_filters"])
+    ]
+    return "\n".join(part for part in parts if part)
+
+
+RETRYABLE_STATUS_CODES = (408, 409, 425, 429, 500, 502, 503, 504, 529)
+CONTEXT_LENGTH_RE = re.compile(
+    r"context.length|maximum context|too many tokens|prompt is too long|"
+    r"reduce the length",
+    re.IGNORECASE,
+)
+MAX_BACKOFF_SECONDS = 60
+
+
+class LLMError(Exception):
+    # kind is one of: rate_limit, auth, context_length, timeout, server, client,
+    # bad_response, deadline
+    def __init__(self, kind, message, status=None):
+        super().__init__(message)
+        self.kind = kind
+        self.status = status
+
+    def __str__(self):
+        status = f" (HTTP {self.status})" if self.status else ""
+        return f"[{self.kind}]{status} {super().__str__()}"
+
+
+def classify_http_error(response):
+    message, code = response.text, ""
+    try:
+        body = response.json()
+    except ValueError:
+        body = None
+    if isinstance(body, dict):
+        error = body.get("error", body)
+        if isinstance(error, dict):
+            message = error.get("message", "")
+            code = error.get("code") or error.get("type") or ""
+        else:
+            message = str(error)
+    message = message or response.text or response.reason
+    detail = f"{code}: {message}" if code else message
+
+    if response.status_code in (401, 403):
+        return LLMError("auth", detail, response.status_code)
+    if response.status_code == 429:
+        return LLMError("rate_limit", detail, response.status_code)
+    if response.status_code == 413 or (
+        response.status_code == 400 and CONTEXT_LENGTH_RE.search(detail)
+    ):
+        return LLMError("context_length", detail, response.status_code)
+    if response.status_code >= 500 or response.status_code in RETRYABLE_STATUS_CODES:
+        return LLMError("server", detail, response.status_code)
+    return LLMError("client", detail, response.status_code)
+
+
+def get_retry_after(response):
+    value = response.headers.get("Retry-After") if response is not None else None
+    if not value:
+        return None
+    try:
+        return max(0.0, float(value))
+    except ValueError:
+        pass
+    try:
+        return max(0.0, parsedate_to_datetime(value).timestamp() - time.time())
+    except (TypeError, ValueError):
+        return None
+
+
+class RateLimiter:
+    # Sliding one-minute window shared by all review threads
+    def __init__(self, requests_per_minute):
+        self.requests_per_minute = requests_per_minute
+        self.sent = deque()
+        self.lock = threading.Lock()
+
+    def acquire(self):
+        if self.requests_per_minute <= 0:
+            return
+        while True:
+            with self.lock:
+                now = time.monotonic()
+                while self.sent and now - self.sent[0] >= 60:
+                    self.sent.popleft()
+                if len(self.sent) < self.requests_per_minute:
+                    self.sent.append(now)
+                    return
+                wait = 60 - (now - self.sent[0])
+            print(f"[DEBUG] Requests-per-minute limit reached, waiting {wait:.1f}s")
+            time.sleep(wait)
+
+
+def post_with_retries(
+    url, headers, payload, timeout, max_retries, deadline=None, rate_limiter=None
+):
+    attempt = 0
+    while True:
+        remaining = None if deadline is None else deadline - time.monotonic()
+        if remaining is not None and remaining <= 0:
+            raise LLMError("deadline", "review deadline reached before request")
+        request_timeout = timeout if remaining is None else min(timeout, remaining)
+
+        if rate_limiter is not None:
+            rate_limiter.acquire()
+        response = None
+        try:
+            response = requests.post(
+                url, headers=headers, json=payload, timeout=request_timeout
+            )
+        except requests.Timeout:
+            error = LLMError("timeout", f"no response within {request_timeout:.0f}s")
+        except requests.ConnectionError as e:
+            error = LLMError("server", f"connection failed: {e}")
+        else:
+            if response.ok:
+                return response
+            error = classify_http_error(response)
+            if response.status_code not in RETRYABLE_STATUS_CODES:
+                raise error
+
+        attempt += 1
+        if attempt > max_retries:
+            raise error
+
+        delay = get_retry_after(response)
+        if delay is None:
+            delay = min(MAX_BACKOFF_SECONDS, 2**attempt) + random.uniform(0, 1)
+        if deadline is not None and time.monotonic() + delay >= deadline:
+            raise LLMError(
+                "deadline", f"review deadline reached while retrying after {error}"
+            )
+        print(
+            f"[DEBUG] LLM request failed with {error}, retry {attempt}/{max_retries} in {delay:.1f}s"
+        )
+        time.sleep(delay)
+
+
+class GithubRetry(Retry):
+    # POSTs create reviews, comments and check runs. After a 5xx or a read
+    # timeout GitHub may have created them anyway, so POSTs are only retried
+    # when rate limited or when the connection failed before sending
+    def is_retry(self, method, status_code, has_retry_after=False):
+        if method == "POST":
+            return status_code == 429 and bool(self.total)
+        return super().is_retry(method, status_code, has_retry_after)
+
+
+def create_github_client(github_token, timeout=30, max_retries=5):
+    retry = GithubRetry(
+        total=max_retries,
+        backoff_factor=1,
+        status_forcelist=(429, 500, 502, 503, 504),
+        allowed_methods=frozenset(["GET", "PUT", "PATCH", "DELETE"]),
+        respect_retry_after_header=True,
+        raise_on_status=False,
+    )
+    return Github(github_token, timeout=timeout, retry=retry)
+
+
+JSON_MODES = ("json_schema", "json_object", "none")
+
+
+class LLMProvider:
+    default_base_url = ""
+    default_model = ""
+
+    def __init__(
+        self,
+        api_key,
+        base_url="",
+        model="",
+        temperature=0.2,
+        max_tokens=4096,
+        json_mode="json_object",
+        request_timeout=120,
+        max_retries=5,
+        deadline=None,
+        requests_per_minute=0,
+    ):
+        self.api_key = api_key
+        self.base_url = (base_url or self.default_base_url).rstrip("/")
+        self.model = model or self.default_model
+        self.temperature = temperature
+        self.max_tokens = max_tokens
+        if json_mode not in JSON_MODES:
+            raise ValueError(
+                f"Unknown llm_json_mode '{json_mode}', expected one of: {', '.join(JSON_MODES)}"
+            )
+        self.json_mode = json_mode
+        self.request_timeout = request_timeout
+        self.max_retries = max_retries
+        # time.monotonic() value after which no further requests are made
+        self.deadline = deadline
+        self.rate_limiter = RateLimiter(requests_per_minute)
+
+    def build_request(self, messages, schema=None):
+        raise NotImplementedError
+
+    def extract_text(self, response_json):
+        raise NotImplementedError
+
+    def complete(self, messages, schema=None):
+        url, headers, payload = self.build_request(messages, schema)
+        print(
+            f"[DEBUG] Sending prompt to {type(self).__name__} at {url} (model: {self.model})"
+        )
+        response = post_with_retries(
+            url,
+            headers,
+            payload,
+            timeout=self.request_timeout,
+            max_retries=self.max_retries,
+            deadline=self.deadline,
+            rate_limiter=self.rate_limiter,
+        )
+        try:
+            return self.extract_text(response.json())
+        except (ValueError, KeyError, IndexError, TypeError) as e:
+            raise LLMError(
+                "bad_response",
+                f"unexpected response body ({e}): {response.text[:500]}",
+                response.status_code,
+            )
+
+
+class OpenAIProvider(LLMProvider):
+    # Also covers OpenAI-compatible servers such as vLLM, llama.cpp and Ollama
+    default_base_url = "https://api.openai.com/v1"
+    default_model = "gpt-4o"
+
+    def build_payload(self, messages, schema=None):
+        payload = {
+            "model": self.model,
+            "messages": messages,
+            "temperature": self.temperature,
+        }
+        if self.json_mode == "json_schema" and schema:
+            payload["response_format"] = {
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "code_review",
+                    "strict": True,
+                    "schema": schema,
+                },
+            }
+        elif self.json_mode != "none":
+            payload["response_format"] = {"type": "json_object"}
+        return payload
+
+    def build_request(self, messages, schema=None):
+        headers = {"Content-Type": "application/json"}
+        if self.api_key:
+            headers["Authorization"] = f"Bearer {self.api_key}"
+        payload = self.build_payload(messages, schema)
+        return f"{self.base_url}/chat/completions", headers, payload
+
+    def extract_text(self, response_json):
+        return response_json["choices"][0]["message"]["content"]
+
+
+class AzureOpenAIProvider(OpenAIProvider):
+    default_model = ""
+
+    def __init__(self, api_key, api_version="2024-06-01", **kwargs):
+        super().__init__(api_key, **kwargs)
+        self.api_version = api_version
+        if not self.base_url:
+            raise ValueError("llm_base_url is required for the azure provider")
+        if not self.model:
+            raise ValueError(
+                "llm_model (the deployment name) is required for the azure provider"
+            )
+
+    def build_request(self, messages, schema=None):
+        headers = {"Content-Type": "application/json", "api-key": self.api_key}
+        payload = self.build_payload(messages, schema)
+        # The deployment in the URL selects the model
+        del payload["model"]
+        url = (
+            f"{self.base_url}/openai/deployments/{self.model}/chat/completions"
+            f"?api-version={self.api_version}"
+        )
+        return url, headers, payload
+
+
+class AnthropicProvider(LLMProvider):
+    default_base_url = "https://api.anthropic.com"
+    default_model = "claude-3-5-sonnet-latest"
+
+    def build_request(self, messages, schema=None):
+        headers = {
+            "Content-Type": "application/json",
+            "x-api-key": self.api_key,
+            "anthropic-version": "2023-06-01",
+        }
+        if self.json_mode != "none":
+            # No native JSON mode, so prefill the reply with the opening brace
+            messages = messages + [{"role": "assistant", "content": "{"}]
+        payload = {
+            "model": self.model,
+            "messages": messages,
+            "temperature": self.temperature,
+            "max_tokens": self.max_tokens,
+        }
+        return f"{self.base_url}/v1/messages", headers, payload
+
+    def extract_text(self, response_json):
+        text = "".join(
+            block.get("text", "")
+            for block in response_json["content"]
+            if block.get("type") == "text"
+        )
+        if self.json_mode != "none":
+            text = "{" + text
+        return text
+
+
+PROVIDERS = {
+    "openai": OpenAIProvider,
+    "azure": AzureOpenAIProvider,
+    "anthropic": AnthropicProvider,
+}
+
+
+def create_provider(
+    provider_name,
+    api_key,
+    base_url="",
+    model="",
+    temperature=0.2,
+    max_tokens=4096,
+    api_version="",
+    json_mode="json_object",
+    request_timeout=120,
+    max_retries=5,
+    deadline=None,
+    requests_per_minute=0,
 ):
-    # Add line numbers to diff content
+    provider_name = (provider_name or "openai").lower()
+    if provider_name not in PROVIDERS:
+        raise ValueError(
+            f"Unknown llm_provider '{provider_name}', expected one of: {', '.join(PROVIDERS)}"
+        )
+
+    kwargs = {
+        "base_url": base_url,
+        "model": model,
+        "temperature": temperature,
+        "max_tokens": max_tokens,
+        "json_mode": json_mode,
+        "request_timeout": request_timeout,
+        "max_retries": max_retries,
+        "deadline": deadline,
+        "requests_per_minute": requests_per_minute,
+    }
+    if provider_name == "azure" and api_version:
+        kwargs["api_version"] = api_version
+
+    provider = PROVIDERS[provider_name](api_key, **kwargs)
+    print(
+        f"[DEBUG] Using LLM provider: {provider_name}, base URL: {provider.base_url}, model: {provider.model}"
+    )
+    return provider
+
+
+# Ordered from least to most severe
+SEVERITIES = ("info", "warning", "error")
+# Mirrors the sections of developer_manual.md
+CATEGORIES = (
+    "naming",
+    "docs",
+    "error-handling",
+    "safety",
+    "performance",
+    "testing",
+    "organization",
+    "dependencies",
+    "tooling",
+)
+SIDES = ("RIGHT", "LEFT")
+
+# Strict-mode structured outputs require every property to be listed as required,
+# so optional fields are expressed as nullable instead
+REVIEW_RESPONSE_SCHEMA = {
+    "type": "object",
+    "properties": {
+        "filename": {"type": "string"},
+        "comments": {
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "line": {"type": "integer"},
+                    "start_line": {"type": ["integer", "null"]},
+                    "side": {"type": ["string", "null"], "enum": [*SIDES, None]},
+                    "comment": {"type": "string"},
+                    "suggestion": {"type": ["string", "null"]},
+                    "severity": {"type": "string", "enum": list(SEVERITIES)},
+                    "category": {"type": "string", "enum": list(CATEGORIES)},
+                    "rule_id": {"type": "string"},
+                },
+                "required": [
+                    "line",
+                    "start_line",
+                    "side",
+                    "comment",
+                    "suggestion",
+                    "severity",
+                    "category",
+                    "rule_id",
+                ],
+                "additionalProperties": False,
+            },
+        },
+    },
+    "required": ["filename", "comments"],
+    "additionalProperties": False,
+}
+
+REPAIR_PROMPT = """Your previous response could not be used: {error}
+Respond again with only a single JSON object matching the requested format, with no other text."""
+
+
+def extract_json_text(llm_text):
+    text = llm_text.strip()
+    fence = re.match(r"^```(?:json)?\s*(.*?)\s*```$", text, re.DOTALL)
+    if fence:
+        text = fence.group(1)
+    if not text.startswith("{"):
+        json_start = text.find("{")
+        json_end = text.rfind("}") + 1
+        if json_start == -1 or json_end == 0:
+            raise ValueError("response does not contain a JSON object")
+        text = text[json_start:json_end]
+    return text
+
+
+def validate_review_response(feedback, filename, rule_ids=()):
+    schema = REVIEW_RESPONSE_SCHEMA
+    if not isinstance(feedback, dict):
+        raise ValueError("top-level value must be a JSON object")
+    unknown = set(feedback) - set(schema["properties"])
+    if unknown:
+        raise ValueError(f"unexpected top-level keys: {', '.join(sorted(unknown))}")
+    if not isinstance(feedback.get("filename"), str):
+        raise ValueError('"filename" must be a string')
+    if feedback["filename"] != filename:
+        print(
+            f"[DEBUG] LLM returned filename {feedback['filename']!r}, expected {filename!r}"
+        )
+        feedback["filename"] = filename
+    if not isinstance(feedback.get("comments"), list):
+        raise ValueError('"comments" must be an array')
+
+    allowed_keys = set(schema["properties"]["comments"]["items"]["properties"])
+    for idx, comment in enumerate(feedback["comments"]):
+        where = f"comments[{idx}]"
+        if not isinstance(comment, dict):
+            raise ValueError(f"{where} must be an object")
+        unknown = set(comment) - allowed_keys
+        if unknown:
+            raise ValueError(
+                f"{where} has unexpected keys: {', '.join(sorted(unknown))}"
+            )
+        line = comment.get("line")
+        if isinstance(line, bool) or not isinstance(line, int):
+            raise ValueError(f"{where}.line must be an integer, got {line!r}")
+        start_line = comment.get("start_line")
+        if start_line is not None:
+            if isinstance(start_line, bool) or not isinstance(start_line, int):
+                raise ValueError(
+                    f"{where}.start_line must be an integer or null, got {start_line!r}"
+                )
+            if start_line > line:
+                raise ValueError(
+                    f"{where}.start_line ({start_line}) must not be after line ({line})"
+                )
+        side = comment.get("side")
+        if side is not None and side not in SIDES:
+            raise ValueError(
+                f"{where}.side must be one of {', '.join(SIDES)} or null, got {side!r}"
+            )
+        body = comment.get("comment")
+        if not isinstance(body, str) or not body.strip():
+            raise ValueError(f"{where}.comment must be a non-empty string")
+        suggestion = comment.get("suggestion")
+        if suggestion is not None and not isinstance(suggestion, str):
+            raise ValueError(f"{where}.suggestion must be a string or null")
+        severity = comment.get("severity")
+        if severity not in SEVERITIES:
+            raise ValueError(
+                f"{where}.severity must be one of {', '.join(SEVERITIES)}, got {severity!r}"
+            )
+        category = comment.get("category")
+        if category not in CATEGORIES:
+            raise ValueError(
+                f"{where}.category must be one of {', '.join(CATEGORIES)}, got {category!r}"
+            )
+        rule_id = comment.get("rule_id")
+        if rule_id not in rule_ids and rule_id != GENERAL_RULE_ID:
+            raise ValueError(
+                f"{where}.rule_id must be a rule ID from the catalogue or {GENERAL_RULE_ID!r}, got {rule_id!r}"
+            )
+    return feedback
+
+
+def parse_review_response(llm_text, filename, rule_ids=()):
+    try:
+        feedback = json.loads(extract_json_text(llm_text))
+    except json.JSONDecodeError as e:
+        raise ValueError(f"invalid JSON: {e}")
+    return validate_review_response(feedback, filename, rule_ids)
+
+
+def number_diff_lines(diff_content):
+    # Added and context lines carry their new line number, removed lines their
+    # old line number prefixed with L so they can be commented on with side LEFT
     numbered_diff = []
-    current_line = 0
+    old_line = new_line = 0
     for line in diff_content.split("\n"):
-        if line.startswith("@@"):
-            # Parse the @@ line to get new starting line number
-            match = re.search(r"\+(\d+)", line)
-            if match:
-                current_line = int(match.group(1)) - 1
+        match = HUNK_HEADER_RE.match(line)
+        if match:
+            old_line = int(match.group(1))
+            new_line = int(match.group(3))
             numbered_diff.append(line)
         elif line.startswith("+"):
-            current_line += 1
-            numbered_diff.append(f"{current_line:4d} | {line}")
+            numbered_diff.append(f"{new_line:>5} | {line}")
+            new_line += 1
         elif line.startswith("-"):
+            numbered_diff.append(f"{'L' + str(old_line):>5} | {line}")
+            old_line += 1
+        elif line.startswith("\\") or not line:
             numbered_diff.append(line)
         else:
-            current_line += 1
-            numbered_diff.append(f"{current_line:4d} | {line}")
+            numbered_diff.append(f"{new_line:>5} | {line}")
+            old_line += 1
+            new_line += 1
+    return "\n".join(numbered_diff)
+
 
-    numbered_diff_content = "\n".join(numbered_diff)
+def review_code_with_llm(
+    filename,
+    diff_content,
+    manual_content,
+    example_contents,
+    provider,
+    max_attempts=3,
+    renamed_from=None,
+    file_context="",
+    automated_findings=(),
+    prompt_additions="",
+):
+    numbered_diff_content = number_diff_lines(diff_content)
+    rules = parse_rule_catalogue(manual_content)
+    rule_ids = {rule["id"] for rule in rules}
+    file_header = f"File: {filename}"
+    if renamed_from:
+        file_header += f" (renamed from {renamed_from})"
+    if file_context:
+        file_context = f"""
+Read-only context (for understanding only, do NOT comment on these lines unless they also appear in the diff below):
+{file_context}
+"""
+    additions = ""
+    if prompt_additions:
+        additions = f"""
+Additional review instructions for this repository:
+{prompt_additions}
+"""
+    automated = ""
+    if automated_findings:
+        automated = f"""
+Already reported by automated checks, do NOT report these findings again:
+{format_automated_findings(automated_findings)}
+"""
 
     prompt = f"""
 You are a code reviewer. Base your review on best practices for safe and efficient code. Give examples where applicable, and reference the developer manual or examples, if they exist, for more information.
@@ -109,93 +2139,348 @@ Below is the developer manual and examples(they may be empty). :
 Developer Manual:
 {manual_content}
 
+Rules of the developer manual, by ID:
+{format_rule_catalogue(rules)}
+
 Examples:
 {example_contents}
-
-Now, review the following code diff. Line numbers are shown at the start of each line:
-
-File: {filename}
+{additions}
+Now, review the following code diff. Line numbers are shown at the start of each line.
+Removed lines are numbered with their old line number prefixed by "L"; to comment on them use side "LEFT" and the number without the "L". All other lines use side "RIGHT".
+To comment on a range of lines (e.g. a whole function or impl block), set "start_line" to the first line and "line" to the last line of the range; both must be on the same side and inside the same diff hunk.
+When you can propose a concrete fix for added or context lines, put the complete replacement code for exactly the commented line(s) in "suggestion", keeping the original indentation. It replaces those lines verbatim, so include no line numbers, diff markers or explanations.
+Give every comment a severity: "error" for bugs, unsafe code or clear violations of the developer manual, "warning" for likely problems or weaker violations, and "info" for minor suggestions.
+Give every comment the category matching the developer manual section it relates to.
+Cite the rule each comment enforces in "rule_id", using an ID from the list of rules above. Use "{GENERAL_RULE_ID}" only when no rule covers the finding.
+
+{file_header}
+{file_context}{automated}
 Diff:
 {numbered_diff_content}
 
-Provide your feedback in the following JSON format:
+Provide your feedback in the following JSON format, and respond with only this JSON object:
 {{
   "filename": "{filename}",
   "comments": [
     {{
       "line": integer,  # Use the line numbers shown in the diff
-      "comment": "string"
+      "start_line": integer | null,  # First line of a multi-line range, or null
+      "side": "RIGHT" | "LEFT" | null,  # LEFT for removed lines, defaults to RIGHT
+      "comment": "string",
+      "suggestion": "string" | null,  # Replacement code for the line(s), or null
+      "severity": "info" | "warning" | "error",
+      "category": {" | ".join(f'"{category}"' for category in CATEGORIES)},
+      "rule_id": "string"  # ID of the violated rule, or "{GENERAL_RULE_ID}"
     }},
     ...
   ]
 }}
 """
 
-    headers = {
-        "Content-Type": "application/json",
-        "Authorization": f"Bearer {api_key}",
-    }
-    payload = {
-        "model": "gpt-4o",
-        "messages": [{"role": "user", "content": prompt}],
-        "temperature": 0.2,
-    }
-
-    response = requests.post(
-        "https://api.openai.com/v1/chat/completions", headers=headers, json=payload
+    messages = [{"role": "user", "content": prompt}]
+    last_error = None
+    for attempt in range(1, max_attempts + 1):
+        llm_text = provider.complete(messages, REVIEW_RESPONSE_SCHEMA)
+        print(f"[DEBUG] LLM response (attempt {attempt}/{max_attempts}):", llm_text)
+        try:
+            return parse_review_response(llm_text, filename, rule_ids)
+        except ValueError as e:
+            last_error = e
+            print(f"[DEBUG] Invalid LLM response for {filename}: {e}")
+            messages = messages + [
+                {"role": "assistant", "content": llm_text},
+                {"role": "user", "content": REPAIR_PROMPT.format(error=e)},
+            ]
+
+    raise ValueError(
+        f"LLM response still invalid after {max_attempts} attempts: {last_error}"
     )
 
-    return response.json()
 
+# Bump whenever the prompt or response format changes so cached reviews are invalidated
+PROMPT_VERSION = "9"
 
-def get_position_in_diff(diff, target_line):
-    print("[DEBUG] Calculating position in diff for target_line:", target_line)
-    diff_lines = diff.split("\n")
-    position = None
-    current_line = None
-    current_position = 0
 
-    for line in diff_lines:
-        # Reset position counting at each hunk header
-        if line.startswith("@@"):
-            match = re.search(r"\+(\d+)(?:,(\d+))?", line)
-            if match:
-                current_line = int(match.group(1)) - 1
-                current_position = 0
+def compute_cache_key(
+    filename, diff_content, manual_content, example_contents, model, prompt_additions=""
+):
+    digest = hashlib.sha256()
+    for part in (
+        PROMPT_VERSION,
+        model,
+        filename,
+        manual_content,
+        example_contents,
+        prompt_additions,
+        diff_content,
+    ):
+        digest.update(part.encode("utf-8"))
+        digest.update(b"\0")
+    return digest.hexdigest()
+
+
+def load_cached_review(cache_dir, cache_key):
+    if not cache_dir:
+        return None
+    cache_path = os.path.join(cache_dir, f"{cache_key}.json")
+    if not os.path.exists(cache_path):
+        return None
+    try:
+        with open(cache_path, "r") as f:
+            comments = json.load(f)["comments"]
+    except (OSError, ValueError, KeyError) as e:
+        print(f"[DEBUG] Ignoring unreadable cache entry {cache_path}: {e}")
+        return None
+    for c in comments:
+        # Findings that were never published (e.g. cut by max_comments or
+        # a failed post) are handled as new ones
+        c["cached"] = c.pop("published", True)
+    return comments
+
+
+def store_cached_review(cache_dir, cache_key, filename, comments, published_ids):
+    if not cache_dir:
+        return
+    os.makedirs(cache_dir, exist_ok=True)
+    cache_path = os.path.join(cache_dir, f"{cache_key}.json")
+    entries = [
+        {
+            **{k: v for k, v in c.items() if k not in ("cached", "rule_url")},
+            "published": bool(c.get("cached")) or id(c) in published_ids,
+        }
+        for c in comments
+    ]
+    with open(cache_path, "w") as f:
+        json.dump({"filename": filename, "comments": entries}, f, indent=2)
+    print(f"[DEBUG] Cached review of {filename} as {cache_key}")
+
+
+def store_published_reviews(cache_dir, cache_entries, published_findings):
+    # Only called once the findings are on the PR, so a failed run is retried
+    published_ids = {id(c) for c in published_findings}
+    for filename, (cache_key, comments) in cache_entries.items():
+        store_cached_review(cache_dir, cache_key, filename, comments, published_ids)
+
+
+def parse_diff_hunks(diff):
+    # For every hunk, the line numbers that can be commented on per side
+    hunks = []
+    hunk = None
+    old_line = new_line = 0
+    for line in diff.split("\n"):
+        match = HUNK_HEADER_RE.match(line)
+        if match:
+            old_line = int(match.group(1))
+            new_line = int(match.group(3))
+            hunk = {"LEFT": set(), "RIGHT": set()}
+            hunks.append(hunk)
+            continue
+        if hunk is None or not line or line.startswith("\\"):
             continue
-
-        current_position += 1
-
         if line.startswith("+"):
-            current_line += 1
-            if current_line == target_line:
-                position = current_position
-                break
+            hunk["RIGHT"].add(new_line)
+            new_line += 1
         elif line.startswith("-"):
-            # Don't increment current_line for removed lines
-            continue
+            hunk["LEFT"].add(old_line)
+            old_line += 1
         else:
-            # Context line
-            current_line += 1
-
-    if position is not None:
-        print(f"[DEBUG] Found position {position} for line {target_line}")
-        # Print surrounding context for verification
-        start_idx = max(0, diff_lines.index(line) - 2)
-        end_idx = min(len(diff_lines), diff_lines.index(line) + 3)
-        print("[DEBUG] Context around found position:")
-        for i in range(start_idx, end_idx):
-            print(f"  {diff_lines[i]}")
-    else:
-        print(f"[DEBUG] Could not find position for line {target_line}")
+            hunk["RIGHT"].add(new_line)
+            hunk["LEFT"].add(old_line)
+            old_line += 1
+            new_line += 1
+    return hunks
+
+
+def get_comment_anchor(diff, line, side="RIGHT", start_line=None):
+    if start_line == line:
+        start_line = None
+    first_line = line if start_line is None else start_line
+    for hunk in parse_diff_hunks(diff):
+        hunk_lines = hunk[side]
+        if all(n in hunk_lines for n in range(first_line, line + 1)):
+            anchor = {"line": line, "side": side}
+            if start_line is not None:
+                anchor["start_line"] = start_line
+                anchor["start_side"] = side
+            return anchor
+    return None
+
+
+COMMENT_MARKER = "<!-- llm-code-review:comment -->"
+BOT_LOGIN = "github-actions[bot]"
+
+
+def normalize_comment_body(body):
+    body = body.replace(COMMENT_MARKER, "")
+    # Rule links point at the head commit, which changes on every push
+    body = re.sub(r"\]\([^)\s]*\)", "]", body).lower()
+    body = re.sub(r"[^\w\s]", " ", body)
+    return " ".join(body.split())
+
+
+def get_existing_bot_comments(pull_request):
+    existing = []
+    for comment in pull_request.get_review_comments():
+        body = comment.body or ""
+        if COMMENT_MARKER not in body and comment.user.login != BOT_LOGIN:
+            continue
+        existing.append(
+            {
+                "comment": comment,
+                "path": comment.path,
+                # Outdated comments only keep the line they were originally posted on
+                "line": comment.raw_data.get("line")
+                or comment.raw_data.get("original_line"),
+                "side": comment.raw_data.get("side") or "RIGHT",
+                "normalized": normalize_comment_body(body),
+            }
+        )
+    print(f"[DEBUG] Found {len(existing)} existing review comments from the bot")
+    return existing
 
-    return position
+
+def find_duplicate_comment(existing_comments, path, line, side, body, similarity):
+    normalized = normalize_comment_body(body)
+    best_match, best_ratio = None, 0.0
+    for existing in existing_comments:
+        if (existing["path"], existing["line"], existing["side"]) != (path, line, side):
+            continue
+        ratio = difflib.SequenceMatcher(
+            None, existing["normalized"], normalized
+        ).ratio()
+        if ratio > best_ratio:
+            best_match, best_ratio = existing, ratio
+    if best_match is None or best_ratio < similarity:
+        return None, best_ratio
+    return best_match, best_ratio
+
+
+SEVERITY_LABELS = {"info": "ℹ️ Info", "warning": "⚠️ Warning", "error": "🛑 Error"}
 
 
-def post_comments(comments, diffs, repo_full_name, pr_number, commit_id, github_token):
+def format_comment_body(comment):
+    label = SEVERITY_LABELS[comment["severity"]]
+    rule = f"`{comment['rule_id']}`"
+    if comment.get("rule_url"):
+        rule = f"[{rule}]({comment['rule_url']})"
+    return f"**{label}** · `{comment['category']}` · {rule}\n\n{comment['comment']}"
+
+
+def count_blocking_findings(comments, fail_on, overrides=()):
+    count = 0
+    for c in comments:
+        threshold = get_path_setting(overrides, c["filename"], "fail_on", fail_on)
+        if threshold == "none":
+            continue
+        if SEVERITIES.index(c["severity"]) >= SEVERITIES.index(threshold):
+            count += 1
+    return count
+
+
+def filter_findings(comments, min_severity, overrides=()):
+    kept = []
+    for c in comments:
+        threshold = get_path_setting(
+            overrides, c["filename"], "min_severity", min_severity
+        )
+        if SEVERITIES.index(c["severity"]) >= SEVERITIES.index(threshold):
+            kept.append(c)
+    if len(kept) < len(comments):
+        print(
+            f"[DEBUG] Dropped {len(comments) - len(kept)} findings below min_severity"
+        )
+    return kept
+
+
+def limit_findings(comments, max_comments):
+    # Cached findings were already posted, the limit applies to new ones
+    new_comments = [c for c in comments if not c.get("cached")]
+    if not max_comments or len(new_comments) <= max_comments:
+        return comments
+    most_severe = sorted(
+        new_comments, key=lambda c: SEVERITIES.index(c["severity"]), reverse=True
+    )[:max_comments]
+    kept_ids = {id(c) for c in most_severe}
+    print(
+        f"[DEBUG] Keeping the {max_comments} most severe of {len(new_comments)} new findings"
+    )
+    return [c for c in comments if c.get("cached") or id(c) in kept_ids]
+
+
+def get_comment_suggestion(comment):
+    suggestion = comment.get("suggestion")
+    if suggestion is None or comment.get("automated"):
+        # Tool suggestions are exact, a trailing empty line is an inserted line
+        return suggestion
+    return suggestion.rstrip("\n")
+
+
+def render_suggestion(body, suggestion):
+    # Lengthen the fence if the suggested code itself contains one
+    fence = "```"
+    while fence in suggestion:
+        fence += "`"
+    return f"{body}\n\n{fence}suggestion\n{suggestion}\n{fence}"
+
+
+def get_new_side_lines(diff):
+    new_lines = {}
+    new_line = 0
+    for line in diff.split("\n"):
+        match = HUNK_HEADER_RE.match(line)
+        if match:
+            new_line = int(match.group(3))
+        elif line.startswith("+") or line.startswith(" "):
+            new_lines[new_line] = line[1:]
+            new_line += 1
+    return new_lines
+
+
+def verify_suggestion(head_content, file_diff, anchor, suggestion):
+    if anchor["side"] != "RIGHT":
+        return "suggestions can only replace lines of the new file"
+    start_line = anchor.get("start_line", anchor["line"])
+    file_lines = head_content.split("\n")
+    if anchor["line"] > len(file_lines):
+        return f"line {anchor['line']} is past the end of the file"
+    # The head may have moved since the diff was reviewed
+    reviewed_lines = get_new_side_lines(file_diff)
+    for line_no in range(start_line, anchor["line"] + 1):
+        if reviewed_lines.get(line_no) != file_lines[line_no - 1]:
+            return f"line {line_no} changed since the diff was reviewed"
+    current = "\n".join(file_lines[start_line - 1 : anchor["line"]])
+    if suggestion == current:
+        return "suggestion is identical to the current code"
+    return None
+
+
+def get_head_file_content(repo, path, commit_id, cache):
+    if path not in cache:
+        try:
+            contents = repo.get_contents(path, ref=commit_id)
+            cache[path] = contents.decoded_content.decode("utf-8")
+        except Exception as e:
+            print(f"[DEBUG] Could not fetch {path} at {commit_id}: {e}")
+            cache[path] = None
+    return cache[path]
+
+
+def post_comments(
+    comments,
+    diffs,
+    repo_full_name,
+    pr_number,
+    commit_id,
+    github_token,
+    dedupe_similarity=0.85,
+    reviewed_files=(),
+    cached_files=(),
+    review_errors=(),
+    skipped_files=(),
+):
     from github import GithubException
 
-    g = Github(github_token)
+    g = create_github_client(github_token)
     repo = g.get_repo(repo_full_name)
 
     # Re-fetch the PR to ensure we have the latest head commit
@@ -210,61 +2495,1035 @@ def post_comments(comments, diffs, repo_full_name, pr_number, commit_id, github_
     )
     print("[DEBUG] pr.head.sha:", pull_request.head.sha)
 
+    existing_comments = get_existing_bot_comments(pull_request)
+    head_file_cache = {}
+    review_comments = []
+    posted_findings = []
+    unplaced_findings = []
+    # Findings already on the PR as an identical or updated comment
+    existing_findings = []
+
     for comment in comments:
+        if comment.get("cached"):
+            # Posted by an earlier run that reviewed the same diff
+            continue
+
         filename = comment["filename"]
         line = comment["line"]
-        body = comment["comment"]
+        side = comment.get("side") or "RIGHT"
+        start_line = comment.get("start_line")
+        body = format_comment_body(comment)
 
-        print(f"[DEBUG] Attempting to post comment on {filename} at line {line}")
+        print(
+            f"[DEBUG] Attempting to post comment on {filename} at line {line} ({side})"
+        )
         file_diff = diffs.get(filename)
         if not file_diff:
             print(f"[DEBUG] No diff found for {filename}, skipping this comment.")
+            unplaced_findings.append(comment)
             continue
 
-        position = get_position_in_diff(file_diff, line)
-        print(f"[DEBUG] Computed position for file {filename}, line {line}: {position}")
-
-        if position is None:
+        anchor = get_comment_anchor(file_diff, line, side, start_line)
+        if anchor is None:
             print(
-                f"[DEBUG] Could not find position for file {filename} line {line}, skipping this comment."
+                f"[DEBUG] Lines {start_line or line}-{line} ({side}) of {filename} are not inside a single diff hunk, skipping this comment."
+            )
+            unplaced_findings.append(comment)
+            continue
+        print(f"[DEBUG] Computed anchor for file {filename}: {anchor}")
+
+        suggestion = get_comment_suggestion(comment)
+        if suggestion is not None:
+            head_content = get_head_file_content(
+                repo, filename, commit_id, head_file_cache
+            )
+            if head_content is None:
+                problem = "head file content unavailable"
+            else:
+                problem = verify_suggestion(
+                    head_content, file_diff, anchor, suggestion
+                )
+            if problem:
+                print(
+                    f"[DEBUG] Dropping suggestion for {filename} line {line}: {problem}"
+                )
+            else:
+                body = render_suggestion(body, suggestion)
+
+        duplicate, ratio = find_duplicate_comment(
+            existing_comments, filename, line, side, body, dedupe_similarity
+        )
+        if duplicate is not None:
+            if duplicate["comment"] is None:
+                print(
+                    f"[DEBUG] Similar comment already queued for {filename} line {line}, skipping."
+                )
+                existing_findings.append(comment)
+                continue
+            if duplicate["normalized"] == normalize_comment_body(body):
+                print(
+                    f"[DEBUG] Identical comment already exists on {filename} line {line}, skipping."
+                )
+                existing_findings.append(comment)
+                continue
+            try:
+                duplicate["comment"].edit(f"{body}\n\n{COMMENT_MARKER}")
+                duplicate["normalized"] = normalize_comment_body(body)
+                existing_findings.append(comment)
+                print(
+                    f"Updated similar existing comment on {filename} line {line} (similarity {ratio:.2f})"
+                )
+            except GithubException as e:
+                print(f"GitHub API Error: {e.status}, {e.data}")
+            continue
+
+        review_comments.append(
+            {"path": filename, "body": f"{body}\n\n{COMMENT_MARKER}", **anchor}
+        )
+        posted_findings.append(comment)
+        # Also guards against repeats within this run, e.g. from overlapping chunks
+        existing_comments.append(
+            {
+                "comment": None,
+                "path": filename,
+                "line": line,
+                "side": side,
+                "normalized": normalize_comment_body(body),
+            }
+        )
+
+    if not review_comments and not review_errors:
+        print("[DEBUG] No new comments to post, skipping the review.")
+        return existing_findings
+
+    summary = build_review_summary(
+        reviewed_files,
+        cached_files,
+        posted_findings,
+        unplaced_findings,
+        review_errors,
+        skipped_files=skipped_files,
+    )
+    try:
+        pull_request.create_review(
+            commit=commit, body=summary, event="COMMENT", comments=review_comments
+        )
+        print(f"Review posted successfully with {len(review_comments)} comments")
+    except GithubException as e:
+        print(f"GitHub API Error: {e.status}, {e.data}")
+        if e.status != 422 or not review_comments:
+            raise
+        # A single comment GitHub can't anchor rejects the whole review, so fall
+        # back to listing every finding in the summary body
+        print("[DEBUG] Retrying as a summary-only review")
+        summary = build_review_summary(
+            reviewed_files,
+            cached_files,
+            [],
+            unplaced_findings + posted_findings,
+            review_errors,
+            skipped_files=skipped_files,
+        )
+        pull_request.create_review(commit=commit, body=summary, event="COMMENT")
+        print("Summary-only review posted successfully")
+    # Unplaced findings are listed in the summary
+    return existing_findings + posted_findings + unplaced_findings
+
+
+def build_review_summary(
+    reviewed_files,
+    cached_files,
+    posted_findings,
+    unplaced_findings,
+    review_errors,
+    posted_label="new comments",
+    skipped_files=(),
+):
+    reviewed = f"Reviewed **{len(reviewed_files)}** files"
+    if cached_files:
+        reviewed += f" ({len(cached_files)} unchanged since the last review)"
+    lines = [
+        "### LLM Code Review",
+        "",
+        f"{reviewed}, with **{len(posted_findings)}** {posted_label}.",
+    ]
+
+    findings = posted_findings + unplaced_findings
+    if findings:
+        counts = {}
+        categories = {}
+        for finding in findings:
+            counts[finding["severity"]] = counts.get(finding["severity"], 0) + 1
+            categories[finding["category"]] = categories.get(finding["category"], 0) + 1
+        lines += ["", "| Severity | Count |", "| --- | --- |"]
+        for severity in SEVERITIES[::-1]:
+            if severity in counts:
+                lines.append(f"| {severity} | {counts[severity]} |")
+        lines += ["", "| Category | Count |", "| --- | --- |"]
+        for category in CATEGORIES:
+            if category in categories:
+                lines.append(f"| {category} | {categories[category]} |")
+        lines += ["", "| Rule | Count |", "| --- | --- |"]
+        for rule_id, count in count_rule_violations(findings).items():
+            lines.append(f"| `{rule_id}` | {count} |")
+
+    if unplaced_findings:
+        lines += [
+            "",
+            f"<details><summary>Findings outside the diff ({len(unplaced_findings)})</summary>",
+            "",
+        ]
+        for finding in unplaced_findings:
+            lines.append(
+                f"- `{finding['filename']}` line {finding['line']}: {finding['comment']}"
+            )
+        lines += ["", "</details>"]
+
+    if review_errors or skipped_files:
+        # A file may fail in several chunks, list it once with every failure kind
+        skipped = {}
+        for error in review_errors:
+            kinds = skipped.setdefault(error["file"], [])
+            if error["kind"] not in kinds:
+                kinds.append(error["kind"])
+        for skipped_file in skipped_files:
+            skipped.setdefault(skipped_file["file"], []).append(skipped_file["reason"])
+        lines += [
+            "",
+            f"<details><summary>Skipped files ({len(skipped)})</summary>",
+            "",
+        ]
+        for filename, kinds in skipped.items():
+            lines.append(f"- `{filename}`: {', '.join(kinds)}")
+        lines += ["", "</details>"]
+
+    return "\n".join(lines)
+
+
+CHECK_RUN_NAME = "LLM Code Review"
+ANNOTATION_LEVELS = {"info": "notice", "warning": "warning", "error": "failure"}
+# GitHub accepts at most 50 annotations per check run request
+MAX_ANNOTATIONS_PER_REQUEST = 50
+MAX_CHECK_SUMMARY_LENGTH = 65535
+# Workflow command equivalents of the annotation levels
+WORKFLOW_COMMANDS = {"notice": "notice", "warning": "warning", "failure": "error"}
+
+
+def get_check_conclusion(comments, blocking_count):
+    if blocking_count:
+        return "failure"
+    if any(c["severity"] != "info" for c in comments):
+        return "neutral"
+    return "success"
+
+
+def escape_workflow_command(value, is_property=False):
+    value = value.replace("%", "%25").replace("\r", "%0D").replace("\n", "%0A")
+    if is_property:
+        value = value.replace(":", "%3A").replace(",", "%2C")
+    return value
+
+
+def publish_job_annotations(annotations, summary):
+    # Fallback for tokens without checks: write, e.g. on forked PRs
+    summary_path = os.environ.get("GITHUB_STEP_SUMMARY")
+    if summary_path:
+        with open(summary_path, "a") as f:
+            f.write(f"{summary}\n")
+    for annotation in annotations:
+        properties = ",".join(
+            f"{key}={escape_workflow_command(str(value), is_property=True)}"
+            for key, value in (
+                ("file", annotation["path"]),
+                ("line", annotation["start_line"]),
+                ("endLine", annotation["end_line"]),
+                ("title", annotation["title"]),
             )
+        )
+        command = WORKFLOW_COMMANDS[annotation["annotation_level"]]
+        message = escape_workflow_command(annotation["message"])
+        print(f"::{command} {properties}::{message}")
+    print(
+        f"Published the job summary and {len(annotations)} workflow annotations instead"
+    )
+
+
+def publish_check_run(
+    repo,
+    commit_id,
+    comments,
+    blocking_count,
+    reviewed_files=(),
+    cached_files=(),
+    review_errors=(),
+    skipped_files=(),
+):
+    annotations = []
+    unplaced_findings = []
+    for comment in comments:
+        if comment.get("side") == "LEFT":
+            # Annotations can only point at lines of the head commit
+            unplaced_findings.append(comment)
             continue
+        annotations.append(
+            {
+                "path": comment["filename"],
+                "start_line": comment.get("start_line") or comment["line"],
+                "end_line": comment["line"],
+                "annotation_level": ANNOTATION_LEVELS[comment["severity"]],
+                "title": f"{comment['severity']}: {comment['rule_id']}",
+                "message": comment["comment"],
+            }
+        )
+    annotated = [c for c in comments if c not in unplaced_findings]
+
+    summary = build_review_summary(
+        reviewed_files,
+        cached_files,
+        annotated,
+        unplaced_findings,
+        review_errors,
+        posted_label="annotations",
+        skipped_files=skipped_files,
+    )
+    if len(summary) > MAX_CHECK_SUMMARY_LENGTH:
+        summary = summary[: MAX_CHECK_SUMMARY_LENGTH - 20] + "\n\n_(truncated)_"
+    conclusion = get_check_conclusion(comments, blocking_count)
+    title = f"{len(comments)} findings" if comments else "No findings"
+
+    batches = [
+        annotations[i : i + MAX_ANNOTATIONS_PER_REQUEST]
+        for i in range(0, len(annotations), MAX_ANNOTATIONS_PER_REQUEST)
+    ] or [[]]
+    from github import GithubException
+
+    try:
+        check_run = repo.create_check_run(
+            name=CHECK_RUN_NAME,
+            head_sha=commit_id,
+            status="completed",
+            conclusion=conclusion,
+            output={"title": title, "summary": summary, "annotations": batches[0]},
+        )
+    except GithubException as e:
+        if e.status != 403:
+            raise
+        print(
+            f"::warning::Could not create the check run, missing checks: write ({e.status})"
+        )
+        publish_job_annotations(annotations, summary)
+        return
+    # Further annotations are appended by updating the same check run
+    for batch in batches[1:]:
+        check_run.edit(
+            output={"title": title, "summary": summary, "annotations": batch}
+        )
+    print(
+        f"Check run '{CHECK_RUN_NAME}' created with {len(annotations)} annotations, conclusion: {conclusion}"
+    )
+
+
+SARIF_SCHEMA = "https://json.schemastore.org/sarif-2.1.0.json"
+SARIF_LEVELS = {"info": "note", "warning": "warning", "error": "error"}
+
+
+def build_sarif_rules(catalogues):
+    sarif_rules = []
+    seen_ids = set()
+    for catalogue in catalogues:
+        for rule in catalogue["rules"]:
+            # A rule ID defined by several manuals is listed once, from the
+            # first manual defining it
+            if rule["id"] not in seen_ids:
+                seen_ids.add(rule["id"])
+                sarif_rules.append(build_sarif_rule(rule, catalogue["manual_url"]))
+    sarif_rules.append(
+        {
+            "id": GENERAL_RULE_ID,
+            "name": GENERAL_RULE_ID.title(),
+            "shortDescription": {"text": "Not covered by the developer manual"},
+        }
+    )
+    return sarif_rules
+
+
+def build_sarif_rule(rule, manual_url=""):
+    sarif_rule = {
+        "id": rule["id"],
+        "name": rule["id"].title().replace("-", ""),
+        "shortDescription": {"text": rule["text"]},
+        "fullDescription": {
+            "text": f"Developer manual, {rule['section']}: {rule['text']}"
+        },
+        "properties": {"section": rule["section"]},
+    }
+    if manual_url:
+        sarif_rule["helpUri"] = f"{manual_url}#{rule['anchor']}"
+    return sarif_rule
+
+
+def write_sarif(sarif_path, comments, catalogues):
+    rules = build_sarif_rules(catalogues)
+    rule_index = {rule["id"]: idx for idx, rule in enumerate(rules)}
+
+    results = []
+    for comment in comments:
+        if comment.get("side") == "LEFT":
+            # Removed lines don't exist in the analyzed commit
+            continue
+        results.append(
+            {
+                "ruleId": comment["rule_id"],
+                "ruleIndex": rule_index[comment["rule_id"]],
+                "level": SARIF_LEVELS[comment["severity"]],
+                "message": {"text": comment["comment"]},
+                "properties": {"category": comment["category"]},
+                "locations": [
+                    {
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": comment["filename"],
+                                "uriBaseId": "%SRCROOT%",
+                            },
+                            "region": {
+                                "startLine": comment.get("start_line")
+                                or comment["line"],
+                                "endLine": comment["line"],
+                            },
+                        }
+                    }
+                ],
+            }
+        )
+
+    sarif = {
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [
+            {
+                "tool": {
+                    "driver": {
+                        "name": CHECK_RUN_NAME,
+                        "informationUri": "https://github.com/tobratland/actions",
+                        "rules": rules,
+                    }
+                },
+                "results": results,
+            }
+        ],
+    }
+    sarif_dir = os.path.dirname(sarif_path)
+    if sarif_dir:
+        os.makedirs(sarif_dir, exist_ok=True)
+    with open(sarif_path, "w") as f:
+        json.dump(sarif, f, indent=2)
+    print(f"[DEBUG] Wrote {len(results)} SARIF results to {sarif_path}")
+
+
+def write_findings_report(
+    output_dir,
+    comments,
+    reviewed_files,
+    review_errors,
+    pr_number,
+    commit_id,
+    model,
+    skipped_files=(),
+):
+    os.makedirs(output_dir, exist_ok=True)
+    findings_file = os.path.join(output_dir, "findings.json")
+    with open(findings_file, "w") as f:
+        json.dump(
+            {
+                "pull_request": pr_number,
+                "commit_id": commit_id,
+                "model": model,
+                "prompt_version": PROMPT_VERSION,
+                "reviewed_files": reviewed_files,
+                "skipped_files": list(skipped_files),
+                "findings": comments,
+                "errors": review_errors,
+            },
+            f,
+            indent=2,
+        )
+
+    lines = [
+        build_review_summary(
+            reviewed_files,
+            [],
+            comments,
+            [],
+            review_errors,
+            posted_label="findings",
+            skipped_files=skipped_files,
+        ),
+        "",
+        f"_Dry run on commit {commit_id} with model `{model}`, prompt version {PROMPT_VERSION}._",
+    ]
+    current_file = None
+    for comment in comments:
+        if comment["filename"] != current_file:
+            current_file = comment["filename"]
+            lines += ["", f"#### `{current_file}`"]
+        body = format_comment_body(comment)
+        if comment.get("suggestion"):
+            body = render_suggestion(body, comment["suggestion"])
+        side = " (removed line)" if comment.get("side") == "LEFT" else ""
+        lines += ["", f"**Line {comment['line']}{side}**", "", body]
+
+    report_file = os.path.join(output_dir, "report.md")
+    with open(report_file, "w") as f:
+        f.write("\n".join(lines) + "\n")
+    print(f"[DEBUG] Wrote dry-run findings to {findings_file} and {report_file}")
+    return findings_file, report_file
+
+
+REVIEW_MARKER_RE = re.compile(
+    r"<!-- llm-code-review:last-reviewed-sha=([0-9a-f]{7,40}) -->"
+)
+
+
+def find_review_marker_comment(pull_request):
+    marker_comment = None
+    for comment in pull_request.get_issue_comments():
+        if REVIEW_MARKER_RE.search(comment.body or ""):
+            marker_comment = comment
+    return marker_comment
+
+
+def get_last_reviewed_sha(pull_request):
+    marker_comment = find_review_marker_comment(pull_request)
+    if marker_comment is None:
+        print("[DEBUG] No previous review marker found on the PR")
+        return None
+    sha = REVIEW_MARKER_RE.search(marker_comment.body).group(1)
+    print(f"[DEBUG] Last reviewed SHA from marker comment: {sha}")
+    return sha
+
+
+def record_reviewed_sha(pull_request, sha):
+    body = (
+        f"LLM code review last ran on commit {sha}.\n"
+        f"<!-- llm-code-review:last-reviewed-sha={sha} -->"
+    )
+    from github import GithubException
+
+    try:
+        marker_comment = find_review_marker_comment(pull_request)
+        if marker_comment is None:
+            pull_request.create_issue_comment(body)
+        else:
+            marker_comment.edit(body)
+    except GithubException as e:
+        # Read-only tokens, e.g. on forked PRs, can't comment
+        print(f"[DEBUG] Could not record last reviewed SHA: {e.status}, {e.data}")
+        return
+    print(f"[DEBUG] Recorded last reviewed SHA: {sha}")
+
+
+def record_review_error(review_errors, filename, error):
+    kind = error.kind if isinstance(error, LLMError) else "invalid_response"
+    review_errors.append({"file": filename, "kind": kind, "message": str(error)})
+    # Workflow command so the failure shows up as an annotation on the job
+    print(f"::warning file={filename}::LLM review failed ({kind}): {error}")
+
+
+def write_error_outputs(review_errors):
+    set_output("error_count", len(review_errors))
+    set_output("errors", json.dumps(review_errors))
+    if review_errors:
+        counts = {}
+        for error in review_errors:
+            counts[error["kind"]] = counts.get(error["kind"], 0) + 1
+        summary = ", ".join(f"{kind}: {count}" for kind, count in counts.items())
+        print(f"[DEBUG] {len(review_errors)} review errors ({summary})")
+
+
+def review_file(
+    filename,
+    diff_content,
+    manual_content,
+    examples,
+    provider,
+    diff_token_budget,
+    max_parse_attempts,
+    cache_dir="",
+    renamed_from=None,
+    head_content=None,
+    context_options=None,
+    max_examples=2,
+    example_token_budget=4000,
+    pre_checks=True,
+    tool_findings=(),
+    prompt_additions="",
+):
+    example_contents = select_examples(
+        examples, filename, diff_content, max_examples, example_token_budget
+    )
+    cache_key = compute_cache_key(
+        filename,
+        diff_content,
+        manual_content,
+        example_contents,
+        provider.model,
+        prompt_additions,
+    )
+    cached_comments = load_cached_review(cache_dir, cache_key)
+    if cached_comments is not None:
+        print(f"Skipping {filename}, diff unchanged since last review ({cache_key})")
+        for c in cached_comments:
+            c["filename"] = filename
+        # Cargo diagnostics can change without this file's diff changing, e.g.
+        # through a change in another file, so they are not part of the key
+        cached = {(c["line"], c["rule_id"]) for c in cached_comments}
+        new_tool_findings = [
+            f for f in tool_findings if (f["line"], f["rule_id"]) not in cached
+        ]
+        if new_tool_findings:
+            print(
+                f"[DEBUG] Adding {len(new_tool_findings)} new cargo findings to the cached review of {filename}"
+            )
+        return cached_comments + new_tool_findings, [], True, cache_key
 
-        # Show diff context around the computed position for debugging
-        diff_lines = file_diff.split("\n")
-        start_context = max(0, position - 5)
-        end_context = min(len(diff_lines), position + 5)
-        print("[DEBUG] Diff context around computed position:")
-        for c_idx in range(start_context, end_context):
-            print(f"   {c_idx}: {diff_lines[c_idx]}")
+    print(f"Reviewing {filename}...")
 
+    pre_check_findings = []
+    if pre_checks:
+        pre_check_findings = run_pre_checks(
+            filename, head_content, diff_content, parse_rule_catalogue(manual_content)
+        )
+        if pre_check_findings:
+            print(
+                f"[DEBUG] Pre-checks found {len(pre_check_findings)} issues in {filename}"
+            )
+    automated_findings = pre_check_findings + list(tool_findings)
+    # These differ per file, so they come off this file's share of the budget
+    diff_token_budget = max(
+        MIN_DIFF_TOKEN_BUDGET,
+        diff_token_budget
+        - estimate_tokens(format_automated_findings(automated_findings))
+        - estimate_tokens(prompt_additions),
+    )
+
+    print("[DEBUG] Diff content snippet for", filename)
+    for line_idx, dline in enumerate(diff_content.split("\n")[:10]):
+        print(f"   {line_idx}: {dline}")
+
+    context_token_budget = 0
+    if context_options and head_content is not None:
+        # Surrounding code shares the prompt budget with the diff
+        context_token_budget = diff_token_budget // 3
+    chunks = chunk_diff(diff_content, diff_token_budget - context_token_budget)
+    if len(chunks) > 1:
+        print(f"[DEBUG] Split diff for {filename} into {len(chunks)} chunks")
+
+    file_comments = []
+    file_errors = []
+    for chunk_idx, chunk in enumerate(chunks, start=1):
+        print(f"[DEBUG] Reviewing chunk {chunk_idx}/{len(chunks)} of {filename}")
+        file_context = build_file_context(
+            filename, head_content, chunk, context_options, context_token_budget
+        )
         try:
-            pull_request.create_review_comment(
-                body=body, commit_id=commit, path=filename, position=position
+            feedback = review_code_with_llm(
+                filename=filename,
+                diff_content=chunk,
+                manual_content=manual_content,
+                example_contents=example_contents,
+                provider=provider,
+                max_attempts=max_parse_attempts,
+                renamed_from=renamed_from,
+                file_context=file_context,
+                automated_findings=automated_findings,
+                prompt_additions=prompt_additions,
             )
-            print(f"Comment posted successfully on {filename} line {line}")
-        except GithubException as e:
-            print(f"GitHub API Error: {e.status}, {e.data}")
+        except LLMError as e:
+            print(f"Error reviewing chunk {chunk_idx}/{len(chunks)} of {filename}: {e}")
+            if e.kind == "auth":
+                # Every further request would fail the same way
+                raise
+            record_review_error(file_errors, filename, e)
+            if e.kind == "deadline":
+                print(f"[DEBUG] Skipping rest of {filename}, review deadline reached")
+                break
+            if e.kind == "context_length":
+                print(
+                    "[DEBUG] Prompt exceeded the model's context window, consider lowering max_prompt_tokens"
+                )
+            continue
         except Exception as e:
-            print("[DEBUG] Generic exception encountered while posting comment")
-            print("[DEBUG] Exception type:", type(e))
-            print("[DEBUG] Exception content:", str(e))
-            # Not exiting here, continuing to next comment
+            print(f"Error reviewing chunk {chunk_idx}/{len(chunks)} of {filename}: {e}")
+            record_review_error(file_errors, filename, e)
+            continue
+
+        print("[DEBUG] Parsed feedback JSON:", feedback)
+
+        comments = feedback["comments"]
+        for c in comments:
+            c["filename"] = filename
+        file_comments.extend(comments)
+
+    # The model may still repeat an automated finding despite being told about it
+    automated = {(f["line"], f["rule_id"]) for f in automated_findings}
+    file_comments = automated_findings + [
+        c for c in file_comments if (c["line"], c["rule_id"]) not in automated
+    ]
+
+    # Reviews with errors are incomplete and must not be cached
+    return file_comments, file_errors, False, None if file_errors else cache_key
+
+
+def get_incremental_review_diffs(
+    pull_request, repo, head_branch, path_filters, diffs_by_file
+):
+    last_sha = get_last_reviewed_sha(pull_request)
+    if last_sha is None:
+        print("[DEBUG] Falling back to a full review")
+        return diffs_by_file
+
+    incremental_diffs = get_incremental_diffs(
+        repo, last_sha, head_branch, path_filters
+    )
+    if incremental_diffs is None:
+        print("[DEBUG] Falling back to a full review")
+        return diffs_by_file
+
+    incremental_diffs_by_file, _, _ = get_diffs_by_file(incremental_diffs)
+    review_diffs_by_file = {}
+    for filename, diff_content in incremental_diffs_by_file.items():
+        # Files changed since the last review but no longer part of the PR diff
+        # (e.g. reverted changes) have nowhere to anchor comments
+        if filename in diffs_by_file:
+            review_diffs_by_file[filename] = diff_content
+    print(
+        f"[DEBUG] Incremental review covers {len(review_diffs_by_file)} of {len(diffs_by_file)} files"
+    )
+    return review_diffs_by_file
+
+
+def get_diffs_by_file(diffs):
+    diffs_by_file = {}
+    renames = {}
+    skipped_files = []
+    for diff in diffs:
+        filename = get_diff_path(diff)
+        change_type = classify_diff(diff)
+        if change_type in ("deleted", "binary"):
+            print(f"[DEBUG] Skipping {change_type} file: {filename}")
+            skipped_files.append({"file": filename, "reason": change_type})
+            continue
+
+        diff_content = diff.diff.decode("utf-8")
+        if change_type == "renamed":
+            renames[filename] = diff.a_path
+            if not diff_content.strip():
+                print(f"[DEBUG] Skipping pure rename: {diff.a_path} -> {filename}")
+                skipped_files.append(
+                    {"file": filename, "reason": f"renamed from {diff.a_path}"}
+                )
+                continue
+        if not diff_content.strip():
+            # e.g. a mode change or an empty new file, nothing to review
+            reason = "empty file" if change_type == "added" else "no content changes"
+            print(f"[DEBUG] Skipping {filename}: {reason}")
+            skipped_files.append({"file": filename, "reason": reason})
+            continue
+        diffs_by_file[filename] = diff_content
+    return diffs_by_file, renames, skipped_files
+
+
+def review_files(
+    diffs_by_file,
+    file_contexts,
+    provider,
+    diff_token_budget,
+    max_parse_attempts,
+    cache_dir="",
+    max_concurrency=4,
+    renames=None,
+    head_contents=None,
+    context_options=None,
+    max_examples=2,
+    example_token_budget=4000,
+    pre_checks=True,
+    tool_findings=None,
+    prompt_additions="",
+    overrides=(),
+):
+    print(
+        f"[DEBUG] Reviewing {len(diffs_by_file)} files, {max_concurrency} at a time"
+    )
+    all_comments = []
+    cached_files = []
+    review_errors = []
+    cache_entries = {}
+    with ThreadPoolExecutor(max_workers=max_concurrency) as executor:
+        futures = [
+            executor.submit(
+                review_file,
+                filename=filename,
+                diff_content=diff_content,
+                manual_content=file_contexts[filename]["manual_content"],
+                examples=file_contexts[filename]["examples"],
+                provider=provider,
+                diff_token_budget=diff_token_budget,
+                max_parse_attempts=max_parse_attempts,
+                cache_dir=cache_dir,
+                renamed_from=(renames or {}).get(filename),
+                head_content=(head_contents or {}).get(filename),
+                context_options=context_options,
+                max_examples=max_examples,
+                example_token_budget=example_token_budget,
+                pre_checks=pre_checks,
+                tool_findings=(tool_findings or {}).get(filename, ()),
+                prompt_additions=get_prompt_additions(
+                    prompt_additions, overrides, filename
+                ),
+            )
+            for filename, diff_content in diffs_by_file.items()
+        ]
+        try:
+            # Collect in submission order so comments are posted in file order
+            for filename, future in zip(diffs_by_file, futures):
+                comments, file_errors, from_cache, cache_key = future.result()
+                all_comments.extend(comments)
+                review_errors.extend(file_errors)
+                if from_cache:
+                    cached_files.append(filename)
+                if cache_key:
+                    cache_entries[filename] = (cache_key, comments)
+        except LLMError:
+            executor.shutdown(wait=False, cancel_futures=True)
+            raise
+    return all_comments, cached_files, review_errors, cache_entries
+
+
+def format_finding_text(comment):
+    side = " (removed line)" if comment.get("side") == "LEFT" else ""
+    if comment.get("start_line"):
+        lines = f"{comment['start_line']}-{comment['line']}"
+    else:
+        lines = str(comment["line"])
+    text = (
+        f"{comment['filename']}:{lines}{side}: "
+        f"[{comment['severity']}/{comment['category']}/{comment['rule_id']}] "
+        f"{comment['comment']}"
+    )
+    if comment.get("suggestion"):
+        suggestion = "\n".join(
+            f"    {line}" for line in comment["suggestion"].split("\n")
+        )
+        text += f"\n  Suggested change:\n{suggestion}"
+    return text
+
+
+def run_local_review(argv):
+    parser = argparse.ArgumentParser(
+        prog="code_review.py review",
+        description="Review a git range locally, without GitHub.",
+    )
+    parser.add_argument("--base", required=True, help="Base revision, e.g. main")
+    parser.add_argument("--head", default="HEAD", help="Head revision (default: HEAD)")
+    parser.add_argument("--repo", default=".", help="Path to the git repository")
+    parser.add_argument("--format", choices=("text", "json"), default="text")
+    parser.add_argument(
+        "--file-types", default="", help="Comma-separated extensions, e.g. .rs,.toml"
+    )
+    parser.add_argument(
+        "--paths", help="gitignore-style globs, e.g. 'src/**/*.rs,!**/generated/**'"
+    )
+    parser.add_argument(
+        "--api-key",
+        default=os.environ.get("LLM_API_KEY", os.environ.get("OPENAI_API_KEY", "")),
+        help="Provider API key (default: $LLM_API_KEY or $OPENAI_API_KEY)",
+    )
+    parser.add_argument("--provider")
+    parser.add_argument("--base-url")
+    parser.add_argument("--model")
+    parser.add_argument("--fail-on", choices=("none", *SEVERITIES))
+    parser.add_argument(
+        "--config", default=CONFIG_FILE, help=f"Config file (default: {CONFIG_FILE})"
+    )
+    args = parser.parse_args(argv)
+
+    repo_path = os.path.abspath(args.repo)
+    # Progress and debug output goes to stderr so --format json stays parseable
+    with contextlib.redirect_stdout(sys.stderr):
+        # Command line options take precedence over the config file
+        config_path = os.path.join(repo_path, args.config)
+        overrides = apply_repo_config(read_config_file(config_path), config_path)
+        provider = create_provider(
+            provider_name=args.provider or get_input("llm_provider", "openai"),
+            api_key=args.api_key,
+            base_url=args.base_url or get_input("llm_base_url"),
+            model=args.model or get_input("llm_model"),
+            temperature=float(get_input("llm_temperature", "0.2")),
+            max_tokens=int(get_input("llm_max_tokens", "4096")),
+            api_version=get_input("azure_api_version"),
+            json_mode=get_input("llm_json_mode", "json_object"),
+            request_timeout=float(get_input("llm_request_timeout", "120")),
+            max_retries=int(get_input("llm_max_retries", "5")),
+            requests_per_minute=int(get_input("requests_per_minute", "0")),
+        )
+        path_filters = build_path_filters(
+            [ext.strip() for ext in args.file_types.split(",") if ext.strip()],
+            parse_path_patterns(args.paths or get_input("paths")),
+        )
+        repo_git = Repo(repo_path)
+        diffs = get_changed_files(
+            repo_git, args.base, args.head, path_filters, fetch=False
+        )
+        diffs_by_file, renames, skipped_files = get_diffs_by_file(diffs)
+
+        file_contexts = get_file_contexts(
+            repo_path,
+            diffs_by_file,
+            get_input("manual_path", MANUAL_PATH),
+            get_input("examples_path", EXAMPLES_PATH),
+        )
+        max_examples = int(get_input("max_examples", "2"))
+        example_token_budget = int(get_input("example_token_budget", "4000"))
+        diff_token_budget = get_diff_token_budget(
+            int(get_input("max_prompt_tokens", "32000")),
+            get_largest_manual(file_contexts),
+            example_token_budget if max_examples > 0 else 0,
+        )
+        context_options = get_context_options()
+        pre_checks = get_input("pre_checks", "true").lower() == "true"
+        head_contents = {}
+        if context_options or pre_checks:
+            head_contents = read_head_files(repo_git, args.head, diffs_by_file)
+
+        all_comments, _, review_errors, _ = review_files(
+            diffs_by_file,
+            file_contexts=file_contexts,
+            provider=provider,
+            diff_token_budget=diff_token_budget,
+            max_parse_attempts=int(get_input("max_parse_attempts", "3")),
+            max_concurrency=max(1, int(get_input("max_concurrency", "4"))),
+            renames=renames,
+            head_contents=head_contents,
+            context_options=context_options,
+            max_examples=max_examples,
+            example_token_budget=example_token_budget,
+            pre_checks=pre_checks,
+            prompt_additions=get_input("prompt_additions"),
+            overrides=overrides,
+        )
+        all_comments = filter_findings(
+            all_comments, get_input("min_severity", "info"), overrides
+        )
+        fail_on = args.fail_on or get_input("fail_on", "none")
+        blocking_count = count_blocking_findings(all_comments, fail_on, overrides)
+        all_comments = limit_findings(
+            all_comments, int(get_input("max_comments", "0"))
+        )
+
+    if args.format == "json":
+        print(
+            json.dumps(
+                {
+                    "findings": all_comments,
+                    "errors": review_errors,
+                    "skipped_files": skipped_files,
+                },
+                indent=2,
+            )
+        )
+    else:
+        for comment in all_comments:
+            print(format_finding_text(comment))
+        for error in review_errors:
+            print(
+                f"{error['file']}: review failed ({error['kind']}): {error['message']}"
+            )
+        for skipped_file in skipped_files:
+            print(f"{skipped_file['file']}: skipped ({skipped_file['reason']})")
+        print(f"\n{len(all_comments)} findings in {len(diffs_by_file)} files")
+
+    if blocking_count:
+        sys.exit(1)
 
 
 def main():
+    review_errors = []
     try:
-        openai_api_key = sys.argv[1]
+        openai_api_key = sys.argv[1] if len(sys.argv) > 1 else ""
         file_types_input = sys.argv[2] if len(sys.argv) > 2 else ""
         github_token = os.environ.get("GITHUB_TOKEN")
 
+        repo_path = "/github/workspace"
+        git_cmd = Git(repo_path)
+        git_cmd.config("--global", "--add", "safe.directory", repo_path)
+        repo_git = Repo(repo_path)
+
+        config_file = get_input("config_file", CONFIG_FILE)
+        base_ref = os.environ.get("GITHUB_BASE_REF", "")
+        if base_ref:
+            overrides = apply_repo_config(
+                read_base_branch_config(repo_git, base_ref, config_file),
+                f"{config_file} on {base_ref}",
+            )
+        else:
+            print("::warning::GITHUB_BASE_REF not set, ignoring the repository config")
+            overrides = []
+
+        provider = create_provider(
+            provider_name=get_input("llm_provider", "openai"),
+            api_key=openai_api_key,
+            base_url=get_input("llm_base_url"),
+            model=get_input("llm_model"),
+            temperature=float(get_input("llm_temperature", "0.2")),
+            max_tokens=int(get_input("llm_max_tokens", "4096")),
+            api_version=get_input("azure_api_version"),
+            json_mode=get_input("llm_json_mode", "json_object"),
+            request_timeout=float(get_input("llm_request_timeout", "120")),
+            max_retries=int(get_input("llm_max_retries", "5")),
+            requests_per_minute=int(get_input("requests_per_minute", "0")),
+        )
+        max_parse_attempts = int(get_input("max_parse_attempts", "3"))
+        fail_on = get_input("fail_on", "none")
+        if fail_on not in ("none", *SEVERITIES):
+            raise ValueError(
+                f"Unknown fail_on '{fail_on}', expected none, {', '.join(SEVERITIES)}"
+            )
+        min_severity = get_input("min_severity", "info")
+        if min_severity not in SEVERITIES:
+            raise ValueError(
+                f"Unknown min_severity '{min_severity}', expected {', '.join(SEVERITIES)}"
+            )
+        output_mode = get_input("output_mode", "review")
+        if output_mode not in ("review", "check", "both"):
+            raise ValueError(
+                f"Unknown output_mode '{output_mode}', expected review, check or both"
+            )
+        review_mode = get_input("review_mode", "full")
+        if review_mode not in ("full", "incremental"):
+            raise ValueError(
+                f"Unknown review_mode '{review_mode}', expected full or incremental"
+            )
+        if review_mode == "incremental":
+            # Only the files changed since the last review have findings, so a
+            # gate or a SARIF upload would miss the still open ones of the rest
+            gated = fail_on != "none" or any(
+                o.get("fail_on", "none") != "none" for o in overrides
+            )
+            if gated or get_input("sarif_file"):
+                raise ValueError(
+                    "review_mode 'incremental' can't be combined with fail_on or sarif_file"
+                )
+
         if file_types_input:
             file_extensions = [ext.strip() for ext in file_types_input.split(",")]
         else:
             file_extensions = []
         print("[DEBUG] File extensions:", file_extensions)
+        path_filters = build_path_filters(
+            file_extensions, parse_path_patterns(get_input("paths"))
+        )
 
-        g = Github(github_token)
+        g = create_github_client(github_token)
         repo_full_name = os.environ["GITHUB_REPOSITORY"]
         print("[DEBUG] Repo Full Name:", repo_full_name)
         repo = g.get_repo(repo_full_name)
@@ -284,77 +3543,209 @@ def main():
             f"[DEBUG] PR Number: {pr_number}, Base Branch: {base_branch}, Head Branch: {head_branch}, Commit ID: {commit_id}"
         )
 
-        repo_path = "/github/workspace"
         os.chdir(repo_path)
+        diffs = get_changed_files(repo_git, base_branch, head_branch, path_filters)
 
-        git_cmd = Git(repo_path)
-        git_cmd.config("--global", "--add", "safe.directory", repo_path)
-
-        repo_git = Repo(repo_path)
-        diffs = get_changed_files(repo_git, base_branch, head_branch, file_extensions)
-
-        manual_content, example_contents = get_contextual_files(repo_path)
+        review_timeout = float(get_input("review_timeout", "900"))
+        if review_timeout > 0:
+            provider.deadline = time.monotonic() + review_timeout
+            print(f"[DEBUG] Review deadline set to {review_timeout:.0f}s from now")
 
-        all_comments = []
-        diffs_by_file = {}
-        for diff in diffs:
-            if diff.a_path:
-                filename = diff.a_path
-            else:
-                filename = diff.b_path
+        diffs_by_file, renames, skipped_files = get_diffs_by_file(diffs)
+        if get_input("show_renames", "true").lower() != "true":
+            renames = {}
 
-            print(f"Reviewing {filename}...")
-            diff_content = diff.diff.decode("utf-8", errors="replace")
-            diffs_by_file[filename] = diff_content
-
-            print("[DEBUG] Diff content snippet for", filename)
-            for line_idx, dline in enumerate(diff_content.split("\n")[:10]):
-                print(f"   {line_idx}: {dline}")
+        manual_path = get_input("manual_path", MANUAL_PATH)
+        examples_path = get_input("examples_path", EXAMPLES_PATH)
+        file_contexts = get_file_contexts(
+            repo_path, diffs_by_file, manual_path, examples_path
+        )
+        max_examples = int(get_input("max_examples", "2"))
+        example_token_budget = int(get_input("example_token_budget", "4000"))
+        diff_token_budget = get_diff_token_budget(
+            int(get_input("max_prompt_tokens", "32000")),
+            get_largest_manual(file_contexts),
+            example_token_budget if max_examples > 0 else 0,
+        )
 
-            llm_response = review_code_with_llm(
-                filename=filename,
-                diff_content=diff_content,
-                manual_content=manual_content,
-                example_contents=example_contents,
-                api_key=openai_api_key,
+        # Comments are always anchored on the full PR diff, even when only the
+        # commits since the last review are sent to the LLM
+        review_diffs_by_file = diffs_by_file
+        if review_mode == "incremental":
+            review_diffs_by_file = get_incremental_review_diffs(
+                pr, repo_git, head_branch, path_filters, diffs_by_file
             )
 
-            print("[DEBUG] LLM response:", llm_response)
-
-            try:
-                llm_text = llm_response["choices"][0]["message"]["content"]
-                json_start = llm_text.find("{")
-                json_end = llm_text.rfind("}") + 1
-                llm_json_str = llm_text[json_start:json_end]
-                feedback = json.loads(llm_json_str)
-
-                print("[DEBUG] Parsed feedback JSON:", feedback)
-
-                comments = feedback.get("comments", [])
-                for c in comments:
-                    c["filename"] = filename
-                all_comments.extend(comments)
-            except Exception as e:
-                print(f"Error parsing LLM response for {filename}: {e}")
-                if "llm_text" in locals():
-                    print("[DEBUG] LLM text that failed to parse:", llm_text)
-                continue
-
-        post_comments(
-            comments=all_comments,
-            diffs=diffs_by_file,
-            repo_full_name=repo_full_name,
-            pr_number=pr_number,
-            commit_id=commit_id,
-            github_token=github_token,
+        dry_run = get_input("dry_run", "false").lower() == "true"
+        cache_dir = get_input("cache_dir", ".llm-review-cache")
+        if dry_run:
+            # A cache hit means "already posted", which a dry run never does
+            print("[DEBUG] Dry run, review cache disabled")
+            cache_dir = ""
+        if cache_dir and repo_git.git.ls_files("--", cache_dir):
+            # Entries committed by the PR itself could mark files as reviewed
+            # without findings and get them past fail_on
+            print(f"::warning::{cache_dir} is tracked in the repository, ignoring it")
+            cache_dir = ""
+        if cache_dir:
+            # Scoped per PR, since a cache hit means the comments already exist there
+            cache_dir = os.path.join(repo_path, cache_dir, f"pr-{pr_number}")
+            print("[DEBUG] Using review cache directory:", cache_dir)
+
+        context_options = get_context_options()
+        pre_checks = get_input("pre_checks", "true").lower() == "true"
+        head_contents = {}
+        if context_options or pre_checks:
+            head_contents = read_head_files(repo_git, head_branch, review_diffs_by_file)
+        tool_findings = {}
+        catalogue_contexts = dict(file_contexts)
+        if get_input("cargo_checks", "false").lower() == "true":
+            # Lints come from the manual nearest to the workspace manifest
+            workspace_context = get_file_contexts(
+                repo_path, ["Cargo.toml"], manual_path, examples_path
+            )["Cargo.toml"]
+            tool_findings = run_cargo_checks(
+                repo_path,
+                review_diffs_by_file,
+                head_contents,
+                workspace_context["manual_content"],
+            )
+            # Cargo findings cite its rules, even when no reviewed file uses it
+            catalogue_contexts["Cargo.toml"] = workspace_context
+
+        all_comments, cached_files, file_errors, cache_entries = review_files(
+            review_diffs_by_file,
+            file_contexts=file_contexts,
+            provider=provider,
+            diff_token_budget=diff_token_budget,
+            max_parse_attempts=max_parse_attempts,
+            cache_dir=cache_dir,
+            max_concurrency=max(1, int(get_input("max_concurrency", "4"))),
+            renames=renames,
+            head_contents=head_contents,
+            context_options=context_options,
+            max_examples=max_examples,
+            example_token_budget=example_token_budget,
+            pre_checks=pre_checks,
+            tool_findings=tool_findings,
+            prompt_additions=get_input("prompt_additions"),
+            overrides=overrides,
         )
+        review_errors.extend(file_errors)
+
+        if review_mode == "incremental":
+            # Old line numbers of an incremental diff refer to the last reviewed
+            # commit rather than the PR base, so LEFT comments can't be anchored
+            incremental_files = {
+                filename
+                for filename, diff_content in review_diffs_by_file.items()
+                if diff_content != diffs_by_file[filename]
+            }
+            left_comments = [
+                c
+                for c in all_comments
+                if c["filename"] in incremental_files and c.get("side") == "LEFT"
+            ]
+            if left_comments:
+                print(
+                    f"[DEBUG] Dropping {len(left_comments)} comments on removed lines from the incremental diff"
+                )
+                all_comments = [c for c in all_comments if c not in left_comments]
+
+        all_comments = filter_findings(all_comments, min_severity, overrides)
+        catalogues = get_rule_catalogues(catalogue_contexts, repo_full_name, commit_id)
+        link_findings_to_rules(all_comments, file_contexts, catalogues)
+
+        # Cached findings still count, the code they point at is unchanged
+        blocking_count = count_blocking_findings(all_comments, fail_on, overrides)
+
+        if dry_run:
+            dry_run_dir = get_input("dry_run_output", "llm-review-output")
+            findings_file, report_file = write_findings_report(
+                os.path.join(repo_path, dry_run_dir),
+                comments=all_comments,
+                reviewed_files=list(review_diffs_by_file),
+                review_errors=review_errors,
+                pr_number=pr_number,
+                commit_id=commit_id,
+                model=provider.model,
+                skipped_files=skipped_files,
+            )
+            set_output("findings_file", os.path.relpath(findings_file, repo_path))
+            set_output("report_file", os.path.relpath(report_file, repo_path))
+        else:
+            published_findings = []
+            if output_mode in ("review", "both"):
+                # Only review comments are limited, the check run and the SARIF
+                # file get every finding
+                published_findings = post_comments(
+                    comments=limit_findings(
+                        all_comments, int(get_input("max_comments", "0"))
+                    ),
+                    diffs=diffs_by_file,
+                    repo_full_name=repo_full_name,
+                    pr_number=pr_number,
+                    commit_id=commit_id,
+                    github_token=github_token,
+                    dedupe_similarity=float(get_input("dedupe_similarity", "0.85")),
+                    reviewed_files=list(review_diffs_by_file),
+                    cached_files=cached_files,
+                    review_errors=review_errors,
+                    skipped_files=skipped_files,
+                )
+            if output_mode in ("check", "both"):
+                publish_check_run(
+                    repo=repo,
+                    commit_id=commit_id,
+                    comments=all_comments,
+                    blocking_count=blocking_count,
+                    reviewed_files=list(review_diffs_by_file),
+                    cached_files=cached_files,
+                    review_errors=review_errors,
+                    skipped_files=skipped_files,
+                )
+                if output_mode == "check":
+                    published_findings = all_comments
+            store_published_reviews(cache_dir, cache_entries, published_findings)
+        sarif_file = get_input("sarif_file")
+        if sarif_file:
+            write_sarif(
+                os.path.join(repo_path, sarif_file),
+                all_comments,
+                list(catalogues.values()),
+            )
+            set_output("sarif_file", sarif_file)
         print(f"[DEBUG] PR head commit SHA: {pr.head.sha}")
         print(f"[DEBUG] Using commit_id: {commit_id}")
+        # Only incremental runs read the marker, full runs don't need the comment
+        if review_mode == "incremental" and not dry_run:
+            if review_errors:
+                # Files that failed would otherwise be skipped by the next run
+                print("[DEBUG] Review errors, not advancing the last reviewed SHA")
+            else:
+                record_reviewed_sha(pr, commit_id)
+        write_error_outputs(review_errors)
+
+        set_output("findings_count", len(all_comments))
+        set_output("blocking_findings_count", blocking_count)
+        set_output("rule_counts", json.dumps(count_rule_violations(all_comments)))
+        if blocking_count:
+            print(
+                f"::error::{blocking_count} findings at or above severity '{fail_on}'"
+            )
+            sys.exit(1)
 
     except Exception as e:
         print(f"Error: {e}")
+        if isinstance(e, LLMError):
+            print(f"::error::LLM request failed ({e.kind}): {e}")
+            review_errors.append({"file": None, "kind": e.kind, "message": str(e)})
+        write_error_outputs(review_errors)
         sys.exit(1)
 
 
 if __name__ == "__main__":
-    main()
+    if len(sys.argv) > 1 and sys.argv[1] == "review":
+        run_local_review(sys.argv[2:])
+    else:
+        main()
@@ -0,0 +1,78 @@
+import os
+import sys
+import unittest
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), ".."))
+
+from code_review import (  # noqa: E402
+    HUNK_HEADER_RE,
+    chunk_diff,
+    parse_diff_hunks,
+    split_large_hunk,
+)
+
+
+def make_mixed_hunk():
+    body = []
+    for i in range(20):
+        body += [f" context {i}", f"-removed {i}", f"+added {i}", f"+added {i} again"]
+    body.append("\\ No newline at end of file")
+    return "\n".join(["@@ -10,40 +12,60 @@ fn main() {"] + body)
+
+
+def get_side_lines(diff):
+    lines = {"LEFT": set(), "RIGHT": set()}
+    for hunk in parse_diff_hunks(diff):
+        lines["LEFT"] |= hunk["LEFT"]
+        lines["RIGHT"] |= hunk["RIGHT"]
+    return lines
+
+
+class SplitLargeHunkTest(unittest.TestCase):
+    def test_pieces_keep_line_numbers(self):
+        hunk = make_mixed_hunk()
+        pieces = split_large_hunk(hunk, 50)
+
+        self.assertGreater(len(pieces), 1)
+        combined = {"LEFT": set(), "RIGHT": set()}
+        for piece in pieces:
+            piece_lines = get_side_lines(piece)
+            combined["LEFT"] |= piece_lines["LEFT"]
+            combined["RIGHT"] |= piece_lines["RIGHT"]
+        self.assertEqual(combined, get_side_lines(hunk))
+
+    def test_headers_match_piece_bodies(self):
+        for piece in split_large_hunk(make_mixed_hunk(), 50):
+            header, *body = piece.split("\n")
+            match = HUNK_HEADER_RE.match(header)
+            self.assertIsNotNone(match)
+            self.assertEqual(match.group(5), " fn main() {")
+            old_count = sum(1 for line in body if line[:1] in (" ", "-"))
+            new_count = sum(1 for line in body if line[:1] in (" ", "+"))
+            self.assertEqual(int(match.group(2)), old_count)
+            self.assertEqual(int(match.group(4)), new_count)
+
+
+class ChunkDiffTest(unittest.TestCase):
+    def test_small_diff_is_one_chunk(self):
+        diff = "@@ -1,1 +1,1 @@\n-a\n+b"
+
+        self.assertEqual(chunk_diff(diff, 1000), [diff])
+
+    def test_chunks_keep_line_numbers(self):
+        second_hunk = "@@ -200,2 +220,3 @@\n context\n-old\n+new\n+newer"
+        diff = make_mixed_hunk() + "\n" + second_hunk
+        chunks = chunk_diff(diff, 50)
+
+        self.assertGreater(len(chunks), 1)
+        combined = {"LEFT": set(), "RIGHT": set()}
+        for chunk in chunks:
+            self.assertTrue(chunk.startswith("@@ "))
+            chunk_lines = get_side_lines(chunk)
+            combined["LEFT"] |= chunk_lines["LEFT"]
+            combined["RIGHT"] |= chunk_lines["RIGHT"]
+        self.assertEqual(combined, get_side_lines(diff))
+
+
+if __name__ == "__main__":
+    unittest.main()
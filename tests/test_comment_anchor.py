@@ -0,0 +1,64 @@
+import os
+import sys
+import unittest
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), ".."))
+
+from code_review import get_comment_anchor  # noqa: E402
+
+# Old lines 10-13 and new lines 10-14 in the first hunk, old 40-41 and new 41-43
+# in the second
+DIFF = "\n".join(
+    [
+        "@@ -10,4 +10,5 @@ fn main() {",
+        " let a = 1;",
+        "-let b = 2;",
+        "+let b = 3;",
+        "+let c = 4;",
+        " let d = 5;",
+        " let e = 6;",
+        "@@ -40,2 +41,3 @@ fn other() {",
+        " let x = 1;",
+        "+let y = 2;",
+        " let z = 3;",
+    ]
+)
+
+
+class GetCommentAnchorTest(unittest.TestCase):
+    def test_single_line(self):
+        self.assertEqual(get_comment_anchor(DIFF, 12), {"line": 12, "side": "RIGHT"})
+
+    def test_removed_line(self):
+        self.assertEqual(
+            get_comment_anchor(DIFF, 11, side="LEFT"), {"line": 11, "side": "LEFT"}
+        )
+
+    def test_multi_line_range(self):
+        self.assertEqual(
+            get_comment_anchor(DIFF, 13, start_line=11),
+            {"line": 13, "side": "RIGHT", "start_line": 11, "start_side": "RIGHT"},
+        )
+
+    def test_same_start_line_is_single_line(self):
+        self.assertEqual(
+            get_comment_anchor(DIFF, 12, start_line=12), {"line": 12, "side": "RIGHT"}
+        )
+
+    def test_line_outside_hunks(self):
+        self.assertIsNone(get_comment_anchor(DIFF, 20))
+        self.assertIsNone(get_comment_anchor(DIFF, 15, side="LEFT"))
+
+    def test_range_across_hunks(self):
+        self.assertIsNone(get_comment_anchor(DIFF, 42, start_line=13))
+
+    def test_added_line_has_no_left_side(self):
+        # New line 42 is an addition, old line 42 is in no hunk
+        self.assertEqual(
+            get_comment_anchor(DIFF, 42, side="RIGHT"), {"line": 42, "side": "RIGHT"}
+        )
+        self.assertIsNone(get_comment_anchor(DIFF, 42, side="LEFT"))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,80 @@
+import os
+import sys
+import unittest
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), ".."))
+
+from code_review import (  # noqa: E402
+    build_path_filters,
+    glob_to_regex,
+    is_path_included,
+)
+
+
+class GlobToRegexTest(unittest.TestCase):
+    def assertMatches(self, pattern, matching, not_matching):
+        regex = glob_to_regex(pattern)
+        for path in matching:
+            self.assertTrue(regex.match(path), f"{pattern} should match {path}")
+        for path in not_matching:
+            self.assertFalse(regex.match(path), f"{pattern} should not match {path}")
+
+    def test_pattern_without_slash_matches_at_any_depth(self):
+        self.assertMatches(
+            "*.rs", ["main.rs", "src/lib.rs", "crates/a/src/lib.rs"], ["main.rsx"]
+        )
+
+    def test_leading_slash_anchors_to_root(self):
+        self.assertMatches("/build.rs", ["build.rs"], ["crates/a/build.rs"])
+
+    def test_pattern_with_slash_is_anchored(self):
+        self.assertMatches("src/*.rs", ["src/lib.rs"], ["a/src/lib.rs", "src/a/b.rs"])
+
+    def test_double_star(self):
+        self.assertMatches(
+            "crates/**/tests/*.rs",
+            ["crates/tests/a.rs", "crates/a/b/tests/c.rs"],
+            ["tests/a.rs", "crates/a/tests/b/c.rs"],
+        )
+        self.assertMatches("src/**", ["src/a.rs", "src/a/b.rs"], ["lib/src.rs"])
+
+    def test_trailing_slash_matches_directory_contents(self):
+        self.assertMatches(
+            "vendor/", ["vendor/a.rs", "vendor/a/b.rs"], ["vendored/a.rs"]
+        )
+
+    def test_directory_name_matches_everything_below(self):
+        self.assertMatches("target", ["target/debug/a", "a/target/b"], ["targets/a"])
+
+    def test_question_mark_and_character_class(self):
+        self.assertMatches("?.rs", ["a.rs"], ["ab.rs", "/.rs"])
+        self.assertMatches("[ab].rs", ["a.rs", "b.rs"], ["c.rs"])
+        self.assertMatches("[!ab].rs", ["c.rs"], ["a.rs"])
+
+    def test_special_characters_are_literal(self):
+        self.assertMatches("a+b.rs", ["a+b.rs"], ["aab.rs", "a+bxrs"])
+
+
+class IsPathIncludedTest(unittest.TestCase):
+    def test_last_matching_pattern_decides(self):
+        patterns = ["src/**", "!src/gen/**", "src/gen/keep.rs"]
+        path_filters = build_path_filters([], patterns)
+
+        self.assertTrue(is_path_included("src/lib.rs", path_filters))
+        self.assertFalse(is_path_included("src/gen/out.rs", path_filters))
+        self.assertTrue(is_path_included("src/gen/keep.rs", path_filters))
+        self.assertFalse(is_path_included("docs/a.md", path_filters))
+
+    def test_only_negated_patterns_include_the_rest(self):
+        path_filters = build_path_filters([], ["!docs/**"])
+
+        self.assertTrue(is_path_included("src/lib.rs", path_filters))
+        self.assertFalse(is_path_included("docs/a.md", path_filters))
+
+    def test_default_excludes(self):
+        self.assertFalse(is_path_included("Cargo.lock", []))
+        self.assertFalse(is_path_included("crates/a/Cargo.lock", []))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,71 @@
+import os
+import sys
+import unittest
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), ".."))
+
+from code_review import (  # noqa: E402
+    get_comment_suggestion,
+    parse_rustfmt_output,
+    verify_suggestion,
+)
+
+HEAD_CONTENT = "fn a() {}\nfn b() {\n    let x = 1;\n}\n"
+FILE_DIFF = "@@ -0,0 +1,4 @@\n+fn a() {}\n+fn b() {\n+    let x = 1;\n+}\n"
+# Older rustfmt versions name the line with "at line N", newer ones with ":N"
+HEADERS = (
+    "Diff in /repo/src/lib.rs at line 1:",
+    "Diff in /repo/src/lib.rs:1:",
+)
+
+
+class ParseRustfmtOutputTest(unittest.TestCase):
+    def test_insertion_only_hunk(self):
+        for header in HEADERS:
+            with self.subTest(header=header):
+                output = f"{header}\n fn a() {{}}\n fn b() {{\n+\n     let x = 1;\n"
+                findings = parse_rustfmt_output(output, "/repo", "REVIEW-FMT")
+
+                self.assertEqual(len(findings), 1)
+                finding = findings[0]
+                self.assertEqual(finding["filename"], "src/lib.rs")
+                self.assertEqual(finding["line"], 2)
+                self.assertIsNone(finding["start_line"])
+                self.assertEqual(finding["rule_id"], "REVIEW-FMT")
+                # The blank line is inserted by keeping the line above it
+                self.assertEqual(finding["suggestion"], "fn b() {\n")
+
+    def test_replacement_hunk(self):
+        for header in HEADERS:
+            with self.subTest(header=header):
+                output = (
+                    f"{header}\n fn a() {{}}\n-fn b() {{\n-    let x = 1;\n"
+                    "+fn b() {\n+    let x = 1;\n }\n"
+                )
+                findings = parse_rustfmt_output(output, "/repo", "REVIEW-FMT")
+
+                self.assertEqual(len(findings), 1)
+                self.assertEqual(findings[0]["start_line"], 2)
+                self.assertEqual(findings[0]["line"], 3)
+                self.assertEqual(findings[0]["suggestion"], "fn b() {\n    let x = 1;")
+
+
+class CommentSuggestionTest(unittest.TestCase):
+    def test_insertion_suggestion_is_kept(self):
+        output = f"{HEADERS[1]}\n fn a() {{}}\n fn b() {{\n+\n     let x = 1;\n"
+        finding = parse_rustfmt_output(output, "/repo", "REVIEW-FMT")[0]
+
+        suggestion = get_comment_suggestion(finding)
+        self.assertEqual(suggestion, "fn b() {\n")
+        anchor = {"side": "RIGHT", "line": 2}
+        problem = verify_suggestion(HEAD_CONTENT, FILE_DIFF, anchor, suggestion)
+        self.assertIsNone(problem)
+
+    def test_llm_suggestion_trailing_newline_is_stripped(self):
+        comment = {"suggestion": "fn b() {\n"}
+
+        self.assertEqual(get_comment_suggestion(comment), "fn b() {")
+
+
+if __name__ == "__main__":
+    unittest.main()